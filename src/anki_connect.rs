@@ -1,9 +1,13 @@
+use anyhow::{Context, Result};
 use serde::Deserialize;
 use serde::Serialize;
+use std::collections::BTreeMap;
+
+pub const FIELD_NAMES: [&str; 4] = ["word", "reading", "sentence", "definition"];
 
 #[derive(Serialize)]
 pub struct Response {
-    pub result: Option<Box<dyn erased_serde::Serialize>>,
+    pub result: Option<Box<dyn erased_serde::Serialize + Send>>,
     pub error: Option<String>,
 }
 
@@ -16,48 +20,188 @@ impl Response {
         }
     }
 
-    pub fn result(s: impl Serialize + 'static) -> Self {
+    pub fn result(s: impl Serialize + Send + 'static) -> Self {
         Response {
             result: Some(Box::new(s)),
             error: None,
         }
     }
 
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_else(|e| Self::error(e.to_string()).to_json())
+    }
+
     // we need this for compatibility with yomichan
     pub fn version_downgrade(&self) -> String {
         if let Some(r) = &self.result {
-            return serde_json::to_string(r).unwrap();
+            return serde_json::to_string(r)
+                .unwrap_or_else(|e| Self::error(e.to_string()).to_json());
         }
         if let Some(s) = &self.error {
-            return format!(r#"{{"result": null, "error": "{}"}}"#, s);
+            return serde_json::json!({"result": null, "error": s}).to_string();
         }
         "No response.".into()
     }
+
+    /// AnkiConnect answers requests up to version 4 with the bare result
+    pub fn to_json_for(&self, version: i64) -> String {
+        match version {
+            ..=4 => self.version_downgrade(),
+            _ => self.to_json(),
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
 pub struct AnkiConnectAction {
     pub action: String,
+    /// actions batched with `multi` usually don't carry their own version,
+    /// AnkiConnect assumes 4 then
+    #[serde(default = "default_version")]
     pub version: i64,
     pub params: Option<Params>,
+    pub key: Option<String>,
+}
+
+impl AnkiConnectAction {
+    fn params(&self) -> Result<&Params> {
+        self.params.as_ref().context("missing params")
+    }
+
+    pub fn note(&self) -> Result<&Note> {
+        self.params()?
+            .note
+            .as_ref()
+            .context("missing parameter note")
+    }
+
+    pub fn notes(&self) -> Result<&[Note]> {
+        self.params()?
+            .notes()
+            .context("missing parameter notes, it has to be a list of notes")
+    }
+
+    pub fn note_ids(&self) -> Result<&[i64]> {
+        self.params()?
+            .note_ids()
+            .context("missing parameter notes, it has to be a list of note ids")
+    }
+
+    pub fn query(&self) -> Result<&str> {
+        let query = self.params()?.query.as_deref();
+        query.context("missing parameter query")
+    }
+
+    /// the deck of the first note which has one, batched actions included
+    pub fn deck_name(&self) -> Option<&str> {
+        let params = self.params.as_ref()?;
+        let mut notes = params.note.iter().chain(params.notes().unwrap_or_default());
+        notes.find_map(|n| n.deck_name.as_deref()).or_else(|| {
+            let mut actions = params.actions.iter().flatten();
+            actions.find_map(|a| a.deck_name())
+        })
+    }
+
+    pub fn actions(&self) -> Result<&[AnkiConnectAction]> {
+        let actions = self.params()?.actions.as_deref();
+        actions.context("missing parameter actions")
+    }
+}
+
+fn default_version() -> i64 {
+    4
 }
 
 #[derive(Deserialize, Debug)]
 pub struct Params {
     pub note: Option<Note>,
     pub query: Option<String>,
-    pub notes: Option<Vec<Note>>,
+    pub notes: Option<Notes>,
+    pub actions: Option<Vec<AnkiConnectAction>>,
 }
 
+impl Params {
+    pub fn notes(&self) -> Option<&[Note]> {
+        match self.notes {
+            Some(Notes::Notes(ref notes)) => Some(notes),
+            _ => None,
+        }
+    }
+
+    pub fn note_ids(&self) -> Option<&[i64]> {
+        match self.notes {
+            Some(Notes::Ids(ref ids)) => Some(ids),
+            // an empty list looks like a list of notes
+            Some(Notes::Notes(ref notes)) if notes.is_empty() => Some(&[]),
+            _ => None,
+        }
+    }
+}
+
+/// most actions send whole notes, `notesInfo` only sends their ids
 #[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum Notes {
+    Notes(Vec<Note>),
+    Ids(Vec<i64>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
 pub struct Note {
+    pub deck_name: Option<String>,
+    pub model_name: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
     pub fields: Fields,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Fields {
     pub word: String,
     pub reading: Option<String>,
     pub sentence: String,
     pub definition: Option<String>,
 }
+
+/// a single entry of the `notesInfo` result
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NoteInfo {
+    pub note_id: i64,
+    pub model_name: String,
+    pub tags: Vec<String>,
+    pub fields: BTreeMap<String, FieldValue>,
+    pub cards: Vec<i64>,
+}
+
+#[derive(Serialize)]
+pub struct FieldValue {
+    pub value: String,
+    pub order: usize,
+}
+
+impl NoteInfo {
+    pub fn new(note_id: i64, fields: &Fields) -> Self {
+        let values = [
+            fields.word.clone(),
+            fields.reading.clone().unwrap_or_default(),
+            fields.sentence.clone(),
+            fields.definition.clone().unwrap_or_default(),
+        ];
+        let fields = FIELD_NAMES
+            .iter()
+            .zip(values)
+            .enumerate()
+            .map(|(order, (name, value))| (name.to_string(), FieldValue { value, order }))
+            .collect();
+        NoteInfo {
+            note_id,
+            model_name: "jpdb".into(),
+            tags: Vec::new(),
+            fields,
+            // every jpdb vocab is exactly one card
+            cards: vec![note_id],
+        }
+    }
+}
@@ -0,0 +1,311 @@
+//! jpdb's json api, documented at https://jpdb.io/api
+//! it needs the api key from the bottom of the settings page instead of the session cookie
+use crate::backend::{Backend, Card};
+use crate::jpdb::{ScrapingBackend, Site};
+use crate::parsing::VocabId;
+use crate::secret::Secret;
+use anyhow::{anyhow, Context, Result};
+use log::*;
+use reqwest::header::HeaderValue;
+use reqwest::Request;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// a vocabulary entry as returned by /parse
+#[derive(Debug, Deserialize)]
+pub struct Vocabulary {
+    pub vid: u64,
+    pub sid: u64,
+    pub rid: u64,
+    pub spelling: String,
+    pub reading: String,
+}
+
+/// the fields we request from /parse, in the order of [Vocabulary]
+const VOCABULARY_FIELDS: [&str; 5] = ["vid", "sid", "rid", "spelling", "reading"];
+
+impl Vocabulary {
+    pub fn rel_url(&self) -> String {
+        if self.spelling == self.reading {
+            format!("/vocabulary/{}/{}", self.vid, self.spelling)
+        } else {
+            format!(
+                "/vocabulary/{}/{}/{}",
+                self.vid, self.spelling, self.reading
+            )
+        }
+    }
+
+    pub fn vocab_id(&self) -> VocabId {
+        VocabId {
+            v: self.vid.to_string(),
+            s: self.sid.to_string(),
+            r: self.rid.to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ApiError {
+    error_message: String,
+}
+
+async fn call<T: DeserializeOwned>(
+    site: &mut Site,
+    token: &str,
+    endpoint: &str,
+    payload: impl Serialize,
+) -> Result<T> {
+    let url = site.abs_url(format!("/api/v1/{endpoint}"));
+    let mut req = Request::new(reqwest::Method::POST, reqwest::Url::parse(&url)?);
+    let payload = serde_json::to_vec(&payload).context("encoding payload")?;
+    *req.body_mut() = Some(reqwest::Body::from(payload));
+    let headers = req.headers_mut();
+    headers.insert("content-type", HeaderValue::from_static("application/json"));
+    headers.insert("authorization", format!("Bearer {token}").parse()?);
+
+    let res = site
+        .send_request(req)
+        .await
+        .with_context(|| format!("{endpoint} request"))?;
+    let status = res.status();
+    let body = res.text().await?;
+    trace!("{} answered {}: {}", endpoint, status, body);
+    if !status.is_success() {
+        let message = serde_json::from_str::<ApiError>(&body)
+            .map(|e| e.error_message)
+            .unwrap_or(body);
+        return Err(anyhow!(
+            "jpdb api {} failed, status: {} {}",
+            endpoint,
+            status.as_u16(),
+            message
+        ));
+    }
+    serde_json::from_str(&body).with_context(|| format!("decoding {endpoint} answer"))
+}
+
+/// checks whether the api key works
+pub async fn ping(site: &mut Site, token: &str) -> Result<()> {
+    call::<serde_json::Value>(site, token, "ping", json!({})).await?;
+    Ok(())
+}
+
+/// lets jpdb parse the word and picks the vocabulary which fits the spelling and reading best
+async fn find_vocabulary(
+    site: &mut Site,
+    token: &str,
+    word: &str,
+    reading: Option<&str>,
+) -> Result<Option<Vocabulary>> {
+    #[derive(Deserialize)]
+    struct Parsed {
+        vocabulary: Vec<Vocabulary>,
+    }
+    let payload = json!({
+        "text": word,
+        "token_fields": [],
+        "vocabulary_fields": VOCABULARY_FIELDS,
+    });
+    let parsed: Parsed = call(site, token, "parse", payload).await?;
+    debug!("jpdb parsed {} as {:?}", word, parsed.vocabulary);
+
+    let mut candidates: Vec<_> = parsed
+        .vocabulary
+        .into_iter()
+        .filter(|v| v.spelling == word)
+        .collect();
+    if let Some(reading) = reading {
+        if let Some(i) = candidates.iter().position(|v| v.reading == reading) {
+            return Ok(Some(candidates.swap_remove(i)));
+        }
+    }
+    Ok(candidates.into_iter().next())
+}
+
+#[derive(Deserialize)]
+struct ParsedText {
+    /// (vocabulary index, position, length), the index is null for unknown words
+    tokens: Vec<(Option<usize>, usize, usize)>,
+    vocabulary: Vec<Vocabulary>,
+}
+
+impl ParsedText {
+    /// the vocabulary of the token which covers the character position
+    fn vocabulary_at(self, position: usize) -> Option<Vocabulary> {
+        let (index, ..) = self
+            .tokens
+            .iter()
+            .find(|(_, start, length)| (*start..start + length).contains(&position))?;
+        self.vocabulary.into_iter().nth((*index)?)
+    }
+}
+
+/// lets jpdb parse the text and returns the vocabulary at the character position
+async fn vocabulary_at(
+    site: &mut Site,
+    token: &str,
+    text: &str,
+    position: usize,
+) -> Result<Option<Vocabulary>> {
+    let payload = json!({
+        "text": text,
+        "token_fields": ["vocabulary_index", "position", "length"],
+        "vocabulary_fields": VOCABULARY_FIELDS,
+    });
+    let parsed: ParsedText = call(site, token, "parse", payload).await?;
+    Ok(parsed.vocabulary_at(position))
+}
+
+/// vid and sid as numbers, the api doesn't accept strings
+fn vid_sid(id: &VocabId) -> Result<(u64, u64)> {
+    Ok((id.v.parse()?, id.s.parse()?))
+}
+
+async fn add_vocabulary(
+    site: &mut Site,
+    token: &str,
+    deck: serde_json::Value,
+    id: &VocabId,
+) -> Result<()> {
+    let payload = json!({
+        "id": deck,
+        "vocabulary": [vid_sid(id)?],
+    });
+    call::<serde_json::Value>(site, token, "deck/add-vocabulary", payload).await?;
+    Ok(())
+}
+
+async fn set_card_sentence(
+    site: &mut Site,
+    token: &str,
+    id: &VocabId,
+    sentence: &str,
+) -> Result<()> {
+    let (vid, sid) = vid_sid(id)?;
+    let payload = json!({
+        "vid": vid,
+        "sid": sid,
+        "sentence": sentence,
+    });
+    call::<serde_json::Value>(site, token, "set-card-sentence", payload).await?;
+    Ok(())
+}
+
+/// uses the api where it can and the website for everything else
+#[derive(Clone)]
+pub struct ApiBackend {
+    pub site: Site,
+    pub token: Secret,
+    pub website: ScrapingBackend,
+}
+
+impl ApiBackend {
+    /// the website backend if we are logged into it, which the option needs
+    fn website_needed(&mut self, option: &str) -> Result<&mut ScrapingBackend> {
+        if !self.website.logged_in() {
+            return Err(anyhow!(
+                "the api can't do {}, it needs a session_id",
+                option
+            ));
+        }
+        Ok(&mut self.website)
+    }
+}
+
+impl Backend for ApiBackend {
+    fn logged_in(&self) -> bool {
+        true
+    }
+
+    async fn search(&mut self, word: &str, reading: Option<&str>) -> Result<Option<Card>> {
+        match find_vocabulary(&mut self.site, self.token.expose(), word, reading).await {
+            Ok(Some(vocab)) => {
+                return Ok(Some(Card {
+                    detail_url: vocab.rel_url(),
+                    id: Some(vocab.vocab_id()),
+                }))
+            }
+            Ok(None) => info!("The api can't find {}, searching the website.", word),
+            Err(e) => warn!("{:#}, searching the website.", e),
+        }
+        self.website.search(word, reading).await
+    }
+
+    async fn card_in_text(&mut self, text: &str, position: usize) -> Result<Option<Card>> {
+        let vocab = vocabulary_at(&mut self.site, self.token.expose(), text, position).await?;
+        debug!(
+            "jpdb parsed position {} of {} as {:?}",
+            position, text, vocab
+        );
+        Ok(vocab.map(|vocab| Card {
+            detail_url: vocab.rel_url(),
+            id: Some(vocab.vocab_id()),
+        }))
+    }
+
+    async fn vocab_id(&mut self, card: &Card) -> Result<VocabId> {
+        self.website.vocab_id(card).await
+    }
+
+    async fn add_to_deck(&mut self, id: &VocabId, _origin: &str, deck_id: u64) -> Result<()> {
+        add_vocabulary(&mut self.site, self.token.expose(), json!(deck_id), id).await
+    }
+
+    /// the front of the review queue is a special deck in the api
+    async fn forq(&mut self, id: &VocabId, _origin: &str) -> Result<()> {
+        add_vocabulary(&mut self.site, self.token.expose(), json!("forq"), id).await
+    }
+
+    async fn force_unlock(&mut self, id: &VocabId, origin: &str) -> Result<()> {
+        self.website_needed("auto_unlock")?
+            .force_unlock(id, origin)
+            .await
+    }
+
+    async fn mark_unknown(&mut self, id: &VocabId, origin: &str) -> Result<()> {
+        self.website_needed("auto_forget")?
+            .mark_unknown(id, origin)
+            .await
+    }
+
+    async fn set_custom_sentence(&mut self, id: &VocabId, sentence: &str) -> Result<()> {
+        set_card_sentence(&mut self.site, self.token.expose(), id, sentence).await
+    }
+
+    async fn set_custom_definition(&mut self, id: &VocabId, definition: &str) -> Result<()> {
+        self.website_needed("add_custom_definition")?
+            .set_custom_definition(id, definition)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vocabulary_from_array_test() {
+        let example = r#"[1259620, 1406264136, 1437918808, "見事", "みごと"]"#;
+        let vocab: Vocabulary = serde_json::from_str(example).unwrap();
+        assert_eq!("/vocabulary/1259620/見事/みごと", vocab.rel_url());
+        assert_eq!("1406264136", vocab.vocab_id().s);
+    }
+
+    #[test]
+    fn vocabulary_at_test() {
+        let example = r#"{
+            "tokens": [[0, 0, 2], [null, 2, 1], [1, 3, 2]],
+            "vocabulary": [
+                [1259620, 1406264136, 1437918808, "見事", "みごと"],
+                [1, 2, 3, "演技", "えんぎ"]
+            ]
+        }"#;
+        let parsed = || serde_json::from_str::<ParsedText>(example).unwrap();
+        assert_eq!("見事", parsed().vocabulary_at(1).unwrap().spelling);
+        assert!(parsed().vocabulary_at(2).is_none());
+        assert_eq!("演技", parsed().vocabulary_at(3).unwrap().spelling);
+        assert!(parsed().vocabulary_at(5).is_none());
+    }
+}
@@ -0,0 +1,292 @@
+use crate::api::ApiBackend;
+use crate::jpdb::{ScrapingBackend, Site};
+use crate::parsing::VocabId;
+use crate::secret::Secret;
+use crate::Config;
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// a card as found by [Backend::search]
+#[derive(Clone, Debug)]
+pub struct Card {
+    /// relative url of the detail page
+    pub detail_url: String,
+    /// some backends know the id right away, others have to look at the detail page
+    pub id: Option<VocabId>,
+}
+
+/// everything adding a note does with jpdb
+/// `origin` is the relative url of the detail page, the website wants to know it
+pub trait Backend {
+    /// false if changing cards can't work, e.g. scraping without session_id
+    fn logged_in(&self) -> bool;
+    async fn search(&mut self, word: &str, reading: Option<&str>) -> Result<Option<Card>>;
+    /// the card of the word jpdb sees at this character position when it parses the text
+    async fn card_in_text(&mut self, text: &str, position: usize) -> Result<Option<Card>>;
+    async fn vocab_id(&mut self, card: &Card) -> Result<VocabId>;
+    async fn add_to_deck(&mut self, id: &VocabId, origin: &str, deck_id: u64) -> Result<()>;
+    async fn forq(&mut self, id: &VocabId, origin: &str) -> Result<()>;
+    async fn force_unlock(&mut self, id: &VocabId, origin: &str) -> Result<()>;
+    async fn mark_unknown(&mut self, id: &VocabId, origin: &str) -> Result<()>;
+    async fn set_custom_sentence(&mut self, id: &VocabId, sentence: &str) -> Result<()>;
+    async fn set_custom_definition(&mut self, id: &VocabId, definition: &str) -> Result<()>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    Scraping,
+    Api,
+    Memory,
+}
+
+#[derive(Clone)]
+pub enum AnyBackend {
+    Scraping(ScrapingBackend),
+    Api(ApiBackend),
+    Memory(MemoryBackend),
+}
+
+impl AnyBackend {
+    /// the api if there is a key for it, scraping otherwise
+    pub fn new(site: &Site, config: &Config) -> Result<Self> {
+        let scraping = ScrapingBackend {
+            site: site.clone(),
+            logged_in: config.session_id.is_some(),
+        };
+        let api = |token: &Secret| {
+            AnyBackend::Api(ApiBackend {
+                site: site.clone(),
+                token: token.clone(),
+                website: scraping.clone(),
+            })
+        };
+        Ok(match (config.backend, &config.jpdb_api_key) {
+            (Some(BackendKind::Scraping), _) | (None, None) => AnyBackend::Scraping(scraping),
+            (Some(BackendKind::Api) | None, Some(token)) => api(token),
+            (Some(BackendKind::Api), None) => {
+                return Err(anyhow!("The api backend needs the jpdb_api_key option."))
+            }
+            (Some(BackendKind::Memory), _) => AnyBackend::Memory(MemoryBackend::default()),
+        })
+    }
+}
+
+macro_rules! delegate {
+    ($self:ident, $backend:ident => $e:expr) => {
+        match $self {
+            AnyBackend::Scraping($backend) => $e,
+            AnyBackend::Api($backend) => $e,
+            AnyBackend::Memory($backend) => $e,
+        }
+    };
+}
+
+impl Backend for AnyBackend {
+    fn logged_in(&self) -> bool {
+        delegate!(self, b => b.logged_in())
+    }
+
+    async fn search(&mut self, word: &str, reading: Option<&str>) -> Result<Option<Card>> {
+        delegate!(self, b => b.search(word, reading).await)
+    }
+
+    async fn card_in_text(&mut self, text: &str, position: usize) -> Result<Option<Card>> {
+        delegate!(self, b => b.card_in_text(text, position).await)
+    }
+
+    async fn vocab_id(&mut self, card: &Card) -> Result<VocabId> {
+        delegate!(self, b => b.vocab_id(card).await)
+    }
+
+    async fn add_to_deck(&mut self, id: &VocabId, origin: &str, deck_id: u64) -> Result<()> {
+        delegate!(self, b => b.add_to_deck(id, origin, deck_id).await)
+    }
+
+    async fn forq(&mut self, id: &VocabId, origin: &str) -> Result<()> {
+        delegate!(self, b => b.forq(id, origin).await)
+    }
+
+    async fn force_unlock(&mut self, id: &VocabId, origin: &str) -> Result<()> {
+        delegate!(self, b => b.force_unlock(id, origin).await)
+    }
+
+    async fn mark_unknown(&mut self, id: &VocabId, origin: &str) -> Result<()> {
+        delegate!(self, b => b.mark_unknown(id, origin).await)
+    }
+
+    async fn set_custom_sentence(&mut self, id: &VocabId, sentence: &str) -> Result<()> {
+        delegate!(self, b => b.set_custom_sentence(id, sentence).await)
+    }
+
+    async fn set_custom_definition(&mut self, id: &VocabId, definition: &str) -> Result<()> {
+        delegate!(self, b => b.set_custom_definition(id, definition).await)
+    }
+}
+
+/// pretends to be jpdb without talking to it, for trying things out and for tests
+/// every word exists, ids are handed out in the order words are searched
+#[derive(Clone, Default)]
+pub struct MemoryBackend {
+    pub state: Arc<Mutex<MemoryState>>,
+}
+
+#[derive(Default, Debug)]
+pub struct MemoryState {
+    /// (spelling, reading) of every word searched so far, the index is the vocab id
+    pub vocabulary: Vec<(String, String)>,
+    pub decks: HashMap<u64, Vec<VocabId>>,
+    pub forq: Vec<VocabId>,
+    pub unlocked: Vec<VocabId>,
+    pub unknown: Vec<VocabId>,
+    pub sentences: HashMap<VocabId, String>,
+    pub definitions: HashMap<VocabId, String>,
+}
+
+impl MemoryBackend {
+    fn state(&self) -> std::sync::MutexGuard<'_, MemoryState> {
+        // nothing panics while holding the lock
+        self.state.lock().unwrap()
+    }
+}
+
+impl Backend for MemoryBackend {
+    fn logged_in(&self) -> bool {
+        true
+    }
+
+    async fn search(&mut self, word: &str, reading: Option<&str>) -> Result<Option<Card>> {
+        let mut state = self.state();
+        let key = (word.to_string(), reading.unwrap_or(word).to_string());
+        let index = match state.vocabulary.iter().position(|k| *k == key) {
+            Some(index) => index,
+            None => {
+                state.vocabulary.push(key);
+                state.vocabulary.len() - 1
+            }
+        };
+        let (spelling, reading) = &state.vocabulary[index];
+        let v = index + 1;
+        let detail_url = if spelling == reading {
+            format!("/vocabulary/{}/{}", v, spelling)
+        } else {
+            format!("/vocabulary/{}/{}/{}", v, spelling, reading)
+        };
+        let id = VocabId {
+            v: v.to_string(),
+            s: v.to_string(),
+            r: v.to_string(),
+        };
+        Ok(Some(Card {
+            detail_url,
+            id: Some(id),
+        }))
+    }
+
+    /// every word can be found by searching, so there is nothing to parse
+    async fn card_in_text(&mut self, _text: &str, _position: usize) -> Result<Option<Card>> {
+        Ok(None)
+    }
+
+    async fn vocab_id(&mut self, card: &Card) -> Result<VocabId> {
+        card.id
+            .clone()
+            .ok_or_else(|| anyhow!("can't find vocab id"))
+    }
+
+    async fn add_to_deck(&mut self, id: &VocabId, _origin: &str, deck_id: u64) -> Result<()> {
+        let mut state = self.state();
+        let deck = state.decks.entry(deck_id).or_default();
+        if !deck.contains(id) {
+            deck.push(id.clone());
+        }
+        Ok(())
+    }
+
+    async fn forq(&mut self, id: &VocabId, _origin: &str) -> Result<()> {
+        self.state().forq.push(id.clone());
+        Ok(())
+    }
+
+    async fn force_unlock(&mut self, id: &VocabId, _origin: &str) -> Result<()> {
+        self.state().unlocked.push(id.clone());
+        Ok(())
+    }
+
+    async fn mark_unknown(&mut self, id: &VocabId, _origin: &str) -> Result<()> {
+        self.state().unknown.push(id.clone());
+        Ok(())
+    }
+
+    async fn set_custom_sentence(&mut self, id: &VocabId, sentence: &str) -> Result<()> {
+        self.state().sentences.insert(id.clone(), sentence.into());
+        Ok(())
+    }
+
+    async fn set_custom_definition(&mut self, id: &VocabId, definition: &str) -> Result<()> {
+        self.state()
+            .definitions
+            .insert(id.clone(), definition.into());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anki_connect::Note;
+    use crate::jpdb::JPDBConnection;
+
+    fn memory_connection(config: &str) -> (JPDBConnection, MemoryBackend) {
+        let config = format!("backend = \"memory\"\n{config}");
+        let config = toml::from_str(&config).unwrap();
+        let jpdb = JPDBConnection::new(reqwest::Client::new(), config).unwrap();
+        let AnyBackend::Memory(ref memory) = jpdb.backend else {
+            panic!("not the memory backend");
+        };
+        let memory = memory.clone();
+        (jpdb, memory)
+    }
+
+    fn note(json: &str) -> Note {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[tokio::test]
+    async fn add_note_test() {
+        let config = "auto_add = 5\nauto_forq = true\nadd_mined_sentences = true";
+        let (mut jpdb, memory) = memory_connection(config);
+        let added = jpdb
+            .add_note(&note(
+                r#"{"fields": {"word": "見事", "reading": "みごと", "sentence": "見事な演技"}}"#,
+            ))
+            .await
+            .unwrap();
+        assert_eq!("https://jpdb.io/vocabulary/1/見事/みごと", added.open_url);
+        assert_eq!(crate::parsing::note_id("1", "見事"), Some(added.note_id));
+
+        let state = memory.state();
+        let id = VocabId {
+            v: "1".into(),
+            s: "1".into(),
+            r: "1".into(),
+        };
+        assert_eq!(vec![id.clone()], state.decks[&5]);
+        assert_eq!(vec![id.clone()], state.forq);
+        assert_eq!("見事な演技", state.sentences[&id]);
+        assert!(state.unlocked.is_empty());
+        assert!(state.definitions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn same_word_same_id_test() {
+        let (mut jpdb, _) = memory_connection("auto_add = 5");
+        let fields = r#"{"fields": {"word": "てっきり", "sentence": ""}}"#;
+        let first = jpdb.add_note(&note(fields)).await.unwrap();
+        let other = r#"{"fields": {"word": "見事", "sentence": ""}}"#;
+        jpdb.add_note(&note(other)).await.unwrap();
+        let again = jpdb.add_note(&note(fields)).await.unwrap();
+        assert_eq!(first.note_id, again.note_id);
+    }
+}
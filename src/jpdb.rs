@@ -1,28 +1,160 @@
-use crate::parsing::{find_vocab_id, VocabId};
-use crate::{anki_connect, parsing, Config};
+use crate::backend::{AnyBackend, Backend, Card};
+use crate::lookup_cache::LookupCache;
+use crate::parsing::{
+    find_card_tags, find_decks, find_vocab_id, find_vocabulary_links, Deck, VocabId, VocabularyLink,
+};
+use crate::query::Term;
+use crate::queue::MiningQueue;
+use crate::retry::RetryPolicy;
+use crate::secret::Secret;
+use crate::{anki_connect, normalize, parsing, Config};
 use anyhow::{anyhow, Context as AnyhowContext, Result};
 use log::*;
 use reqwest::header::HeaderValue;
 use reqwest::{Request, Response};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fmt::Display;
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 use tower::buffer::Buffer;
 use tower::limit::{ConcurrencyLimit, RateLimit};
-use tower::{Service, ServiceExt};
+use tower::retry::Retry;
+use tower::{Service, ServiceBuilder, ServiceExt};
 
-pub const DOMAIN: &str = "jpdb.io";
-pub const URL_PREFIX: &str = "https://";
+pub const DEFAULT_BASE_URL: &str = "https://jpdb.io";
+/// how long a request waits for a free place in a full buffer
+const BUFFER_WAIT: Duration = Duration::from_secs(10);
+/// how long duplicate detection reuses a loaded deck, cards added elsewhere show up after that
+const DECK_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// the options which change a card when a note is added
+/// queued notes keep the ones they were mined with
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct MiningOptions {
+    pub auto_add: Option<u64>,
+    pub auto_forq: bool,
+    pub auto_unlock: bool,
+    pub auto_forget: bool,
+    pub add_mined_sentences: bool,
+    pub add_custom_definition: bool,
+}
+
+impl MiningOptions {
+    pub fn any_login_or_detail_options(&self) -> bool {
+        self.auto_add.is_some()
+            || self.auto_forq
+            || self.auto_unlock
+            || self.auto_forget
+            || self.add_mined_sentences
+            || self.add_custom_definition
+    }
+}
 
 #[derive(Clone)]
 pub struct JPDBConnection {
-    pub service: BufferedService,
+    pub site: Site,
     pub config: Config,
+    pub backend: AnyBackend,
+    pub lookups: LookupCache,
+    pub queue: MiningQueue,
+    /// decks which pick another profile, listed so yomichan can offer them
+    pub profile_decks: Vec<Deck>,
+    pub decks: DeckCache,
+}
+
+/// the vocabulary of decks, loading a deck takes a request per page
+/// every popup checks for duplicates, so it is only loaded every few minutes
+#[derive(Clone, Default)]
+pub struct DeckCache {
+    vocabulary: Arc<Mutex<HashMap<u64, LoadedDeck>>>,
+    /// the decks on /learn, every note names its deck
+    list: Arc<Mutex<Option<LoadedList>>>,
+}
+
+/// when it was loaded and its vocabulary
+type LoadedDeck = (Instant, Vec<VocabularyLink>);
+
+/// when the deck list was loaded and the decks
+type LoadedList = (Instant, Vec<Deck>);
+
+impl DeckCache {
+    fn get(&self, deck_id: u64) -> Option<Vec<VocabularyLink>> {
+        let decks = self.vocabulary.lock().unwrap();
+        let (loaded, vocabulary) = decks.get(&deck_id)?;
+        (loaded.elapsed() < DECK_CACHE_TTL).then(|| vocabulary.clone())
+    }
+
+    fn insert(&self, deck_id: u64, vocabulary: Vec<VocabularyLink>) {
+        self.vocabulary
+            .lock()
+            .unwrap()
+            .insert(deck_id, (Instant::now(), vocabulary));
+    }
+
+    /// the deck changed, the next check loads it again
+    fn remove(&self, deck_id: u64) {
+        self.vocabulary.lock().unwrap().remove(&deck_id);
+    }
+
+    fn list(&self) -> Option<Vec<Deck>> {
+        let list = self.list.lock().unwrap();
+        let (loaded, decks) = list.as_ref()?;
+        (loaded.elapsed() < DECK_CACHE_TTL).then(|| decks.clone())
+    }
+
+    fn set_list(&self, decks: Vec<Deck>) {
+        *self.list.lock().unwrap() = Some((Instant::now(), decks));
+    }
+}
+
+impl JPDBConnection {
+    pub fn new(client: reqwest::Client, config: Config) -> Result<Self> {
+        let limits = config.request_limits()?;
+        let service = ServiceBuilder::new()
+            .buffer(limits.buffer_size)
+            .concurrency_limit(limits.concurrency)
+            .rate_limit(limits.requests, limits.per) // so that we don't get IP banned
+            // retries skip the rate limit, their backoff of 1s+ is slower than any allowed limit
+            .retry(RetryPolicy::default())
+            .service(ReqwestService { client });
+        JPDBConnection::with_service(service, config)
+    }
+
+    /// a connection for another profile, it shares the rate limit and lookup cache with this one
+    pub fn sharing(&self, config: Config) -> Result<Self> {
+        let mut jpdb = JPDBConnection::with_service(self.site.service.clone(), config)?;
+        jpdb.lookups = self.lookups.clone();
+        Ok(jpdb)
+    }
+
+    fn with_service(service: BufferedService, config: Config) -> Result<Self> {
+        let site = Site {
+            service,
+            base_url: config.base_url().to_string(),
+            session_id: config.session_id.clone(),
+        };
+        let backend = AnyBackend::new(&site, &config)?;
+        Ok(JPDBConnection {
+            site,
+            config,
+            backend,
+            lookups: LookupCache::default(),
+            queue: MiningQueue::default(),
+            profile_decks: Vec::new(),
+            decks: DeckCache::default(),
+        })
+    }
 }
 
-type BufferedService = Buffer<ConcurrencyLimit<RateLimit<ReqwestService>>, Request>;
+pub type BufferedService =
+    Buffer<ConcurrencyLimit<RateLimit<Retry<RetryPolicy, ReqwestService>>>, Request>;
 
+#[derive(Clone)]
 pub struct ReqwestService {
     pub client: reqwest::Client,
 }
@@ -42,45 +174,498 @@ impl Service<Request> for ReqwestService {
     }
 }
 
-pub async fn send_request(service: &mut BufferedService, req: Request) -> Result<Response> {
-    trace!("Request url: {}", req.url());
-    service
-        .ready()
-        .await
-        .map_err(|e| anyhow!("error getting reqwest client {e}"))?
-        .call(req)
-        .await
-        .map_err(|e| anyhow!("{e}")) // we use this mapping to make our error type sized
+/// jpdb can't be reached or has problems of its own, trying again later can work
+#[derive(Debug)]
+pub struct Unreachable(pub String);
+
+impl Display for Unreachable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "jpdb is unreachable: {}", self.0)
+    }
 }
 
-pub fn abs_url(rel: impl Display) -> String {
-    format!("{}{}{}", URL_PREFIX, DOMAIN, rel)
+impl std::error::Error for Unreachable {}
+
+pub fn is_unreachable(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| cause.is::<Unreachable>())
 }
 
-pub async fn get_request(service: &mut BufferedService, rel_url: &str) -> Result<Response> {
-    let url = abs_url(rel_url);
-    let req = Request::new(reqwest::Method::GET, reqwest::Url::parse(&url)?);
-    send_request(service, req).await
+/// the jpdb instance we talk to through the rate limited service
+#[derive(Clone)]
+pub struct Site {
+    pub service: BufferedService,
+    /// like `https://jpdb.io`, without a trailing slash
+    pub base_url: String,
+    /// sent as the sid cookie, profiles share the service but not the login
+    pub session_id: Option<Secret>,
 }
 
-pub async fn form_request(
-    service: &mut BufferedService,
-    rel_url: &str,
-    payload: impl serde::Serialize,
-) -> Result<Response> {
-    let url = abs_url(rel_url);
-    let mut req = Request::new(reqwest::Method::POST, reqwest::Url::parse(&url)?);
-    let payload = serde_urlencoded::ser::to_string(payload).context("encoding payload")?;
-    *req.body_mut() = Some(reqwest::Body::from(payload));
-    req.headers_mut().insert(
-        "content-type",
-        HeaderValue::from_static("application/x-www-form-urlencoded"),
-    );
-    send_request(service, req).await
+impl Site {
+    pub fn abs_url(&self, rel: impl Display) -> String {
+        format!("{}{}", self.base_url, rel)
+    }
+
+    pub fn search_url(&self, word: &str) -> String {
+        self.abs_url(format!("/search?q={}&lang=english#a", word))
+    }
+
+    pub async fn send_request(&mut self, mut req: Request) -> Result<Response> {
+        trace!("Request url: {}", req.url());
+        if let Some(ref sid) = self.session_id {
+            // reqwest drops it when a redirect leaves the host
+            let cookie = HeaderValue::from_str(&format!("sid={}", sid.expose()))?;
+            req.headers_mut().insert("cookie", cookie);
+        }
+        // the buffer makes us wait while it is full
+        let ready = tokio::time::timeout(BUFFER_WAIT, self.service.ready()).await;
+        let res = ready
+            .map_err(|_| {
+                anyhow!(
+                    "too many requests are waiting for jpdb, try again later or raise buffer_size"
+                )
+            })?
+            .map_err(|e| anyhow!("the request worker stopped: {e}"))?
+            .call(req)
+            .await
+            .map_err(|e| anyhow::Error::new(Unreachable(e.to_string())))?;
+        if res.status().is_server_error() {
+            let status = res.status().as_u16();
+            return Err(Unreachable(format!("jpdb answered with status {status}")).into());
+        }
+        Ok(res)
+    }
+
+    pub async fn get_request(&mut self, rel_url: &str) -> Result<Response> {
+        let url = self.abs_url(rel_url);
+        let req = Request::new(reqwest::Method::GET, reqwest::Url::parse(&url)?);
+        self.send_request(req).await
+    }
+
+    pub async fn form_request(
+        &mut self,
+        rel_url: &str,
+        payload: impl serde::Serialize,
+    ) -> Result<Response> {
+        let url = self.abs_url(rel_url);
+        let mut req = Request::new(reqwest::Method::POST, reqwest::Url::parse(&url)?);
+        let payload = serde_urlencoded::ser::to_string(payload).context("encoding payload")?;
+        *req.body_mut() = Some(reqwest::Body::from(payload));
+        req.headers_mut().insert(
+            "content-type",
+            HeaderValue::from_static("application/x-www-form-urlencoded"),
+        );
+        self.send_request(req).await
+    }
 }
 
 impl JPDBConnection {
-    pub async fn add_note(&mut self, s: &anki_connect::Fields) -> Result<String> {
+    /// for every note: true if it is neither in the auto_add deck nor already known
+    pub async fn can_add_notes(&mut self, notes: &[anki_connect::Note]) -> Result<Vec<bool>> {
+        if self.config.session_id.is_none() {
+            info!("Duplicate detection needs a session_id. All notes count as new.");
+            return Ok(vec![true; notes.len()]);
+        }
+        // usually all notes go to the same deck, so we only load it once
+        let mut decks: HashMap<Option<&str>, Vec<VocabularyLink>> = HashMap::new();
+        let mut result = Vec::with_capacity(notes.len());
+        for note in notes {
+            let deck_name = note.deck_name.as_deref();
+            if let Entry::Vacant(entry) = decks.entry(deck_name) {
+                let auto_add = self.config.auto_add;
+                let deck = match self.target_deck(deck_name, auto_add).await? {
+                    Some(deck_id) => self.deck_vocabulary(deck_id).await?,
+                    None => Vec::new(),
+                };
+                entry.insert(deck);
+            }
+            let s = &note.fields;
+            let can_add = self
+                .can_add_note(s, &decks[&deck_name])
+                .await
+                .unwrap_or_else(|e| {
+                    warn!("Duplicate detection failed for {}: {}", s.word, e);
+                    true
+                });
+            result.push(can_add);
+        }
+        Ok(result)
+    }
+
+    async fn can_add_note(
+        &mut self,
+        s: &anki_connect::Fields,
+        deck: &[VocabularyLink],
+    ) -> Result<bool> {
+        let detail_url = match self.search_exact(&s.word, s.reading.as_deref()).await? {
+            Some(card) => card.detail_url,
+            // jpdb doesn't know it as it was sent, the variants are only searched when mining
+            None => return Ok(true),
+        };
+        let in_deck = vocabulary_key(&detail_url)
+            .is_some_and(|(v, spelling)| deck.iter().any(|e| e.v == v && e.spelling == spelling));
+        if in_deck {
+            debug!("{} is already in the deck", s.word);
+            return Ok(false);
+        }
+        let res = self
+            .site
+            .get_request(&detail_url)
+            .await
+            .context("get detail page")?;
+        let body = &res.text().await?;
+        let tags = find_card_tags(body);
+        debug!("{} has tags {:?}", s.word, tags);
+        let known = tags.iter().any(|t| t == "Known" || t == "Never forget");
+        Ok(!known)
+    }
+
+    /// every card in the deck, in the order jpdb lists them
+    async fn deck_vocabulary(&mut self, deck_id: u64) -> Result<Vec<VocabularyLink>> {
+        if let Some(vocabulary) = self.decks.get(deck_id) {
+            return Ok(vocabulary);
+        }
+        let mut vocabulary = Vec::new();
+        let mut seen = HashSet::new();
+        loop {
+            let rel_url = format!("/deck?id={}&offset={}", deck_id, vocabulary.len());
+            let res = self
+                .site
+                .get_request(&rel_url)
+                .await
+                .context("get deck page")?;
+            let body = &res.text().await?;
+            let before = vocabulary.len();
+            for link in find_vocabulary_links(body) {
+                if seen.insert(link.clone()) {
+                    vocabulary.push(link);
+                }
+            }
+            // the last page doesn't contain anything new
+            if vocabulary.len() == before {
+                break;
+            }
+        }
+        self.decks.insert(deck_id, vocabulary.clone());
+        Ok(vocabulary)
+    }
+
+    /// the decks of the logged in user, always loaded again for clients listing them
+    pub async fn decks(&mut self) -> Result<Vec<Deck>> {
+        self.load_decks(true).await
+    }
+
+    /// the decks, the list from the last few minutes is used unless `fresh`
+    async fn load_decks(&mut self, fresh: bool) -> Result<Vec<Deck>> {
+        // the auto_add deck, yomichan setups from before the deck list still select it
+        let mut decks: Vec<Deck> = (self.config.auto_add.iter())
+            .map(|&id| Deck {
+                id,
+                name: "jpdb".into(),
+            })
+            .collect();
+        // without login we only know the deck from the config
+        if self.config.session_id.is_some() {
+            let cached = if fresh { None } else { self.decks.list() };
+            let found = match cached {
+                Some(found) => found,
+                None => {
+                    let res = self
+                        .site
+                        .get_request("/learn")
+                        .await
+                        .context("get deck list")?;
+                    let body = &res.text().await?;
+                    let found = find_decks(body);
+                    self.decks.set_list(found.clone());
+                    found
+                }
+            };
+            // clients pick decks by name, so later decks with a taken name get their id added
+            let mut names: HashSet<String> = decks.iter().map(|d| d.name.clone()).collect();
+            for mut deck in found {
+                if !names.insert(deck.name.clone()) {
+                    deck.name = format!("{} ({})", deck.name, deck.id);
+                }
+                decks.push(deck);
+            }
+        }
+        decks.extend(self.profile_decks.iter().cloned());
+        Ok(decks)
+    }
+
+    /// id of the deck with this name, `jpdb` is the auto_add deck
+    pub async fn deck_id(&mut self, name: &str) -> Result<Option<u64>> {
+        // jpdb-connect used to only know this one deck
+        if name == "jpdb" {
+            return Ok(self.config.auto_add);
+        }
+        let decks = self.load_decks(false).await?;
+        Ok(decks.into_iter().find(|d| d.name == name).map(|d| d.id))
+    }
+
+    /// the deck named in a note, auto_add if there is none or jpdb doesn't know it
+    async fn target_deck(
+        &mut self,
+        deck_name: Option<&str>,
+        auto_add: Option<u64>,
+    ) -> Result<Option<u64>> {
+        let Some(name) = deck_name else {
+            return Ok(auto_add);
+        };
+        // jpdb is the auto_add deck, the profile decks only picked the profile
+        let profile_deck = self.config.deck_names.iter().any(|d| d == name);
+        if self.config.session_id.is_none() || name == "jpdb" || profile_deck {
+            return Ok(auto_add);
+        }
+        match self.deck_id(name).await? {
+            Some(deck_id) => Ok(Some(deck_id)),
+            None => {
+                info!("There is no deck named '{}', using auto_add.", name);
+                Ok(auto_add)
+            }
+        }
+    }
+
+    /// notes which match all terms, in the auto_add deck if no deck is asked for
+    pub async fn find_notes(&mut self, terms: &[Term]) -> Result<Vec<FoundNote>> {
+        // clients look for words, a whole deck would be a huge answer
+        let narrow = |t: &Term| matches!(t, Term::Word(_) | Term::Reading(_) | Term::NoteIds(_));
+        if !terms.iter().any(narrow) {
+            return Err(anyhow!(
+                "queries need a word, reading or nid term, loading every card of a deck is too slow"
+            ));
+        }
+        let deck_name = terms.iter().find_map(|t| match t {
+            Term::Deck(name) if name != "*" => Some(name),
+            _ => None,
+        });
+        let deck_id = match deck_name {
+            Some(name) => match self.deck_id(name).await? {
+                Some(id) => Some(id),
+                None => return Ok(Vec::new()),
+            },
+            None => None,
+        };
+        let mut entries = self.searchable_deck(deck_id).await?;
+        for term in terms {
+            match term {
+                Term::Deck(_) => {}
+                Term::Word(word) => entries.retain(|e| &e.spelling == word),
+                // spellings of the same vocab share the upper half of the id
+                Term::NoteIds(ids) => {
+                    entries.retain(|e| ids.iter().any(|id| e.v == (id >> 32).to_string()))
+                }
+                Term::Reading(reading) => entries
+                    .retain(|e| e.reading.as_deref().unwrap_or(&e.spelling) == reading.as_str()),
+            }
+        }
+        let mut notes = found_notes(&entries);
+        for term in terms {
+            if let Term::NoteIds(ids) = term {
+                notes.retain(|n| ids.contains(&n.note_id));
+            }
+        }
+        Ok(notes)
+    }
+
+    /// notes with one of the ids in any deck, ids jpdb doesn't know are missing
+    pub async fn notes_info(&mut self, ids: &[i64]) -> Result<Vec<FoundNote>> {
+        let mut notes = Vec::new();
+        // different spellings of a vocab share the upper half of the id and link to each other
+        let vocab_ids: BTreeSet<String> = ids.iter().map(|id| (id >> 32).to_string()).collect();
+        for v in vocab_ids {
+            let res = self
+                .site
+                .get_request(&format!("/vocabulary/{v}"))
+                .await
+                .context("get vocabulary page")?;
+            if !res.status().is_success() {
+                debug!("There is no vocabulary {}", v);
+                continue;
+            }
+            let body = &res.text().await?;
+            let mut links = find_vocabulary_links(body);
+            links.retain(|link| link.v == v);
+            for note in found_notes(&links) {
+                let new = notes.iter().all(|n: &FoundNote| n.note_id != note.note_id);
+                if new && ids.contains(&note.note_id) {
+                    notes.push(note);
+                }
+            }
+        }
+        Ok(notes)
+    }
+
+    /// url of the jpdb page which shows the notes the terms are looking for
+    pub async fn browse_url(&mut self, terms: &[Term]) -> Result<String> {
+        let mut word = None;
+        let mut reading = None;
+        for term in terms {
+            match term {
+                Term::Word(w) => word = Some(w.as_str()),
+                Term::Reading(r) => reading = Some(r.as_str()),
+                Term::NoteIds(ids) => {
+                    // only one page can be opened, the first id jpdb knows wins
+                    let notes = self.notes_info(ids).await?;
+                    let note = ids
+                        .iter()
+                        .find_map(|id| notes.iter().find(|n| n.note_id == *id))
+                        .with_context(|| format!("can't find any note of {:?}", ids))?;
+                    let fields = &note.fields;
+                    return self.open_url(&fields.word, fields.reading.as_deref()).await;
+                }
+                // there is only one deck
+                Term::Deck(_) => {}
+            }
+        }
+        let word = word.context("query needs a word or a note id")?;
+        self.open_url(word, reading).await
+    }
+
+    /// the card for the word, other spellings and dictionary forms are tried if jpdb doesn't know it
+    async fn search(&mut self, word: &str, reading: Option<&str>) -> Result<Option<Card>> {
+        let variants = normalize::search_variants(word, reading);
+        self.search_variants(word, reading, variants).await
+    }
+
+    /// only the word as it was sent, a popup checks many words and can't afford every variant
+    async fn search_exact(&mut self, word: &str, reading: Option<&str>) -> Result<Option<Card>> {
+        let variants = vec![(word.to_string(), reading.map(String::from))];
+        self.search_variants(word, reading, variants).await
+    }
+
+    async fn search_variants(
+        &mut self,
+        word: &str,
+        reading: Option<&str>,
+        variants: Vec<(String, Option<String>)>,
+    ) -> Result<Option<Card>> {
+        if let Some(card) = self.lookups.get(word, reading) {
+            debug!("{} is {} in the lookup cache", word, card.detail_url);
+            return Ok(Some(card));
+        }
+        for (variant, variant_reading) in &variants {
+            let card = self
+                .backend
+                .search(variant, variant_reading.as_deref())
+                .await?;
+            if let Some(card) = card {
+                if variant != word {
+                    // a kana variant finds homophones, きた for 来た would be 北
+                    let spelling = vocabulary_key(&card.detail_url).map(|(_, spelling)| spelling);
+                    let is_variant = |s: &String| variants.iter().any(|(w, _)| w == s);
+                    if !spelling.as_ref().is_some_and(is_variant) {
+                        info!(
+                            "{} found {}, which isn't {}",
+                            variant, card.detail_url, word
+                        );
+                        continue;
+                    }
+                    info!("Found {} as {}", word, variant);
+                }
+                self.lookups.insert(word, reading, &card);
+                return Ok(Some(card));
+            }
+        }
+        Ok(None)
+    }
+
+    /// the word jpdb sees where the mined word is in the sentence
+    /// finds words whose dictionary form differs from what yomichan sent
+    async fn search_in_sentence(&mut self, s: &anki_connect::Fields) -> Option<Card> {
+        let Some(range) = normalize::find_in_sentence(&s.sentence, &s.word) else {
+            debug!("{} doesn't appear in the sentence, can't parse it.", s.word);
+            return None;
+        };
+        let position = s.sentence[..range.start].chars().count();
+        match self.backend.card_in_text(&s.sentence, position).await {
+            Ok(Some(card)) => {
+                info!(
+                    "Found {} as {} by parsing the sentence",
+                    s.word, card.detail_url
+                );
+                Some(card)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                warn!("Parsing the sentence failed: {:#}", e);
+                None
+            }
+        }
+    }
+
+    /// the detail page if it can be found, the search page otherwise
+    async fn open_url(&mut self, word: &str, reading: Option<&str>) -> Result<String> {
+        Ok(match self.search(word, reading).await? {
+            Some(card) => self.site.abs_url(card.detail_url),
+            None => {
+                info!("Can't find details page for: {}", word);
+                self.site.search_url(word)
+            }
+        })
+    }
+
+    async fn searchable_deck(&mut self, deck_id: Option<u64>) -> Result<Vec<VocabularyLink>> {
+        if self.config.session_id.is_none() {
+            return Err(anyhow!("searching notes needs a session_id"));
+        }
+        let deck_id = deck_id
+            .or(self.config.auto_add)
+            .context("searching notes needs a deck or the auto_add option")?;
+        self.deck_vocabulary(deck_id).await
+    }
+
+    /// like add_note, but notes which fail because jpdb is unreachable are queued
+    pub async fn add_note_or_queue(&mut self, note: &anki_connect::Note) -> Result<AddedNote> {
+        let result = self.add_note(note).await;
+        match result {
+            Err(e) if is_unreachable(&e) => {
+                warn!("{:#}, queueing {}", e, note.fields.word);
+                self.queue.push(note.clone(), self.config.mining_options());
+                Err(anyhow!(
+                    "jpdb is unreachable, {} will be added later",
+                    note.fields.word
+                ))
+            }
+            result => result,
+        }
+    }
+
+    /// adds the queued notes in order, stops at the first one jpdb can't be reached for
+    /// notes which fail for other reasons are dropped, retrying won't help them
+    pub async fn replay_queue(&mut self) -> Result<()> {
+        let queue = self.queue.clone();
+        let _replaying = queue.replaying.lock().await;
+        while let Some(queued) = queue.front() {
+            let word = &queued.note.fields.word;
+            let options = queued
+                .options
+                .unwrap_or_else(|| self.config.mining_options());
+            // nobody is waiting for a browser tab, a headless server can't even open one
+            match self.add_note_with(&queued.note, &options, false).await {
+                Ok(_) => info!("Added queued note {}", word),
+                Err(e) if is_unreachable(&e) => return Err(e),
+                Err(e) => error!("Dropping queued note {}: {:#}", word, e),
+            }
+            queue.pop_front();
+        }
+        Ok(())
+    }
+
+    pub async fn add_note(&mut self, note: &anki_connect::Note) -> Result<AddedNote> {
+        let options = self.config.mining_options();
+        self.add_note_with(note, &options, true).await
+    }
+
+    /// `open` false never opens the card, even with auto_open
+    /// a card which can't be found is an error, its search page is opened
+    async fn add_note_with(
+        &mut self,
+        note: &anki_connect::Note,
+        options: &MiningOptions,
+        open: bool,
+    ) -> Result<AddedNote> {
+        let s = &note.fields;
         debug!(
             "add W='{}' R='{}' S='{}' D='{}'",
             s.word,
@@ -88,99 +673,243 @@ impl JPDBConnection {
             s.sentence,
             s.definition.as_deref().unwrap_or_default(),
         );
+        debug!(
+            "deck '{}' model '{}' tags {:?}",
+            note.deck_name.as_deref().unwrap_or_default(),
+            note.model_name.as_deref().unwrap_or_default(),
+            note.tags
+        );
+        let deck_id = self
+            .target_deck(note.deck_name.as_deref(), options.auto_add)
+            .await?;
 
-        let url = format!("https://jpdb.io/search?q={}&lang=english#a", s.word);
-
-        let req = Request::new(reqwest::Method::GET, reqwest::Url::parse(&url)?);
-        let res = send_request(&mut self.service, req)
-            .await
-            .context("search request")?;
-        let body = &res.text().await?;
-        let detail_url =
-            parsing::find_detail_url(body, &s.word, s.reading.as_deref().unwrap_or_default());
-
-        let open_url = if let Ok(ref rel_url) = &detail_url {
-            format!("{}{}{}", URL_PREFIX, DOMAIN, rel_url)
-        } else {
+        let card = match self.search(&s.word, s.reading.as_deref()).await? {
+            Some(card) => Some(card),
+            None => self.search_in_sentence(s).await,
+        };
+        let Some(card) = card else {
             info!("Can't find details page for: {}", s.word);
-            url.into()
+            if self.backend.logged_in() && options.any_login_or_detail_options() {
+                error!("Card can not be handled automatically, because it's detail page can not be found.");
+            } else {
+                // the user can look for it there
+                self.open(&self.site.search_url(&s.word), open)?;
+            }
+            return Err(anyhow::anyhow!("can't find card"));
         };
+        let detail_url = &card.detail_url;
+        let note_id = vocabulary_key(detail_url)
+            .and_then(|(v, spelling)| parsing::note_id(&v, &spelling))
+            .with_context(|| format!("can't make a note id for {detail_url}"))?;
 
-        if self.config.session_id.is_some() {
-            if let Ok(ref detail_url) = detail_url {
-                // look up vocab id on details page
-                let res = get_request(&mut self.service, detail_url)
-                    .await
-                    .context("get detail page")?;
-                let body = &res.text().await?;
-                trace!("Details page:");
-                trace!("{}", body);
-                let vocab = VocabCard { body };
-                if let Some(deck_id) = self.config.auto_add {
-                    info!("Adding card to deck: {}", abs_url(detail_url));
-                    vocab
-                        .add_to_deck(&mut self.service, deck_id, &detail_url)
-                        .await?;
-                }
-                if self.config.auto_unlock {
-                    info!("unlocking: {}", abs_url(detail_url));
-                    vocab.force_unlock(&mut self.service, &detail_url).await?;
+        if self.backend.logged_in() {
+            let vocab_id = self.backend.vocab_id(&card).await;
+            if let Ok(ref id) = vocab_id {
+                if card.id.is_none() {
+                    let card = Card {
+                        detail_url: detail_url.clone(),
+                        id: Some(id.clone()),
+                    };
+                    self.lookups.insert(&s.word, s.reading.as_deref(), &card);
                 }
-                if self.config.auto_forq {
-                    // it appears we don't need to check whether for FORQing is possible
-                    info!("FORQing: {}", abs_url(detail_url));
-                    vocab.forq(&mut self.service, &detail_url).await?;
-                }
-                if self.config.auto_forget {
-                    info!("Mark unknown: {}", abs_url(detail_url));
-                    vocab.mark_unknown(&mut self.service, &detail_url).await?;
-                }
-                if self.config.add_mined_sentences {
-                    info!("Add custom sentence: {}", abs_url(detail_url));
-                    vocab
-                        .set_custom_sentence(&mut self.service, &s.sentence)
-                        .await?;
+            }
+            if deck_id.is_some() || options.any_login_or_detail_options() {
+                let changed = match vocab_id {
+                    Ok(id) => self.change_card(&id, detail_url, deck_id, s, options).await,
+                    Err(e) => Err(e),
+                };
+                // maybe the cached card is outdated, the next try searches again
+                if changed.is_err() {
+                    self.lookups.remove(&s.word, s.reading.as_deref());
                 }
-                if self.config.add_custom_definition {
-                    if let Some(definition) = &s.definition {
-                        info!("Add custom definition: {}", abs_url(detail_url));
-                        vocab
-                            .set_custom_definition(&mut self.service, &definition)
-                            .await?;
+                changed?;
+            }
+        }
+
+        let open_url = self.site.abs_url(detail_url);
+        self.open(&open_url, open)?;
+        Ok(AddedNote { open_url, note_id })
+    }
+
+    /// applies the auto_* and add_* options to the card
+    async fn change_card(
+        &mut self,
+        id: &VocabId,
+        detail_url: &str,
+        deck_id: Option<u64>,
+        s: &anki_connect::Fields,
+        options: &MiningOptions,
+    ) -> Result<()> {
+        let backend = &mut self.backend;
+        if let Some(deck_id) = deck_id {
+            info!("Adding card to deck: {}", self.site.abs_url(detail_url));
+            self.decks.remove(deck_id);
+            backend.add_to_deck(id, detail_url, deck_id).await?;
+        }
+        if options.auto_unlock {
+            info!("unlocking: {}", self.site.abs_url(detail_url));
+            backend.force_unlock(id, detail_url).await?;
+        }
+        if options.auto_forq {
+            // it appears we don't need to check whether for FORQing is possible
+            info!("FORQing: {}", self.site.abs_url(detail_url));
+            backend.forq(id, detail_url).await?;
+        }
+        if options.auto_forget {
+            info!("Mark unknown: {}", self.site.abs_url(detail_url));
+            backend.mark_unknown(id, detail_url).await?;
+        }
+        if options.add_mined_sentences {
+            debug!("custom sentence: {}", s.sentence);
+            if s.sentence.is_empty() {
+                info!("Sentence field was empty. Will not set custom sentence.");
+            } else {
+                // jpdb only shows sentences which contain the word, conjugated or in kana is fine
+                let spelling = vocabulary_key(detail_url).map(|(_, spelling)| spelling);
+                let words = [spelling.as_deref(), Some(&s.word), s.reading.as_deref()];
+                let found = words
+                    .into_iter()
+                    .flatten()
+                    .find_map(|word| normalize::find_in_sentence(&s.sentence, word));
+                match found {
+                    Some(range) => {
+                        let surface = &s.sentence[range];
+                        debug!("{} appears as {} in the sentence", s.word, surface);
+                        info!("Add custom sentence: {}", self.site.abs_url(detail_url));
+                        backend.set_custom_sentence(id, &s.sentence).await?;
                     }
+                    None => warn!(
+                        "{} doesn't appear in the sentence. Will not set custom sentence.",
+                        s.word
+                    ),
                 }
-            } else {
-                if self.config.any_login_or_detail_options() {
-                    error!("Card can not be handled automatically, because it's detail page can not be found.");
-                    return Err(anyhow::anyhow!("can't find card"));
+            }
+        }
+        if options.add_custom_definition {
+            if let Some(definition) = &s.definition {
+                debug!("custom definition: {}", definition);
+                if definition.is_empty() {
+                    info!("Definition field was empty. Will not update definitions.");
+                } else {
+                    info!("Add custom definition: {}", self.site.abs_url(detail_url));
+                    backend.set_custom_definition(id, definition).await?;
                 }
             }
         }
+        Ok(())
+    }
 
-        if self.config.auto_open {
-            info!("Opening: {}", open_url);
-            open::that(&open_url)?;
+    fn open(&self, url: &str, open: bool) -> Result<()> {
+        if open && self.config.auto_open {
+            info!("Opening: {}", url);
+            open::that(url)?;
         }
-        Ok(open_url)
+        Ok(())
     }
 }
 
-struct VocabCard<'a> {
-    body: &'a str,
+/// the ids come from the links, no card has to be loaded
+fn found_notes(entries: &[VocabularyLink]) -> Vec<FoundNote> {
+    let notes = entries.iter().filter_map(|entry| {
+        let Some(note_id) = entry.note_id() else {
+            warn!("Can't make a note id for {}", entry.rel_url());
+            return None;
+        };
+        Some(FoundNote {
+            note_id,
+            fields: anki_connect::Fields {
+                word: entry.spelling.clone(),
+                reading: Some(entry.reading_or_spelling().to_string()),
+                sentence: String::new(),
+                definition: None,
+            },
+        })
+    });
+    notes.collect()
+}
+
+pub struct FoundNote {
+    pub note_id: i64,
+    pub fields: anki_connect::Fields,
+}
+
+pub struct AddedNote {
+    pub open_url: String,
+    /// see [`parsing::note_id`]
+    pub note_id: i64,
+}
+
+/// `(vocab id, spelling)` from a detail url like `/vocabulary/1259620/見事/みごと`
+fn vocabulary_key(detail_url: &str) -> Option<(String, String)> {
+    let mut parts = detail_url.strip_prefix("/vocabulary/")?.split('/');
+    Some((parts.next()?.to_string(), parts.next()?.to_string()))
+}
+
+/// finds cards with the search page and changes them like a browser would
+#[derive(Clone)]
+pub struct ScrapingBackend {
+    pub site: Site,
+    /// whether there is a session_id
+    pub logged_in: bool,
 }
 
-impl VocabCard<'_> {
-    fn find_id(&self) -> Result<VocabId> {
-        Ok(find_vocab_id(self.body).context("can't find vocab id")?)
+impl Backend for ScrapingBackend {
+    fn logged_in(&self) -> bool {
+        self.logged_in
     }
 
-    async fn add_to_deck(
-        &self,
-        service: &mut BufferedService,
-        deck_id: u64,
-        origin: &str,
-    ) -> Result<()> {
-        let vocab_id = self.find_id()?;
+    async fn search(&mut self, word: &str, reading: Option<&str>) -> Result<Option<Card>> {
+        let url = self.site.search_url(word);
+        let req = Request::new(reqwest::Method::GET, reqwest::Url::parse(&url)?);
+        let res = self
+            .site
+            .send_request(req)
+            .await
+            .context("search request")?;
+        let body = &res.text().await?;
+        let results = parsing::find_search_results(body);
+        let Some((chosen, rejected)) = parsing::choose_search_result(results, word, reading) else {
+            debug!("No search result is spelled {}", word);
+            return Ok(None);
+        };
+        let detail_url = chosen.link.rel_url();
+        if rejected.is_empty() {
+            debug!("Found {} for {}", detail_url, word);
+        } else {
+            info!("Chose {} (rank {:?}) for {}", detail_url, chosen.rank, word);
+            for r in rejected {
+                info!("Rejected {} (rank {:?})", r.link.rel_url(), r.rank);
+            }
+        }
+        Ok(Some(Card {
+            detail_url,
+            id: None,
+        }))
+    }
+
+    /// only the api can parse text
+    async fn card_in_text(&mut self, _text: &str, _position: usize) -> Result<Option<Card>> {
+        debug!("Parsing the sentence needs the jpdb_api_key option.");
+        Ok(None)
+    }
+
+    /// looks up the vocab id on the details page
+    async fn vocab_id(&mut self, card: &Card) -> Result<VocabId> {
+        if let Some(ref id) = card.id {
+            return Ok(id.clone());
+        }
+        let res = self
+            .site
+            .get_request(&card.detail_url)
+            .await
+            .context("get detail page")?;
+        let body = &res.text().await?;
+        trace!("Details page:");
+        trace!("{}", body);
+        find_vocab_id(body).context("can't find vocab id")
+    }
+
+    async fn add_to_deck(&mut self, vocab_id: &VocabId, origin: &str, deck_id: u64) -> Result<()> {
         let add_url = format!("/deck/{}/add", deck_id);
         let payload: [(&str, &str); 4] = [
             ("v", &vocab_id.v),
@@ -189,7 +918,9 @@ impl VocabCard<'_> {
             ("origin", origin),
         ];
 
-        let res = form_request(service, &add_url, payload)
+        let res = self
+            .site
+            .form_request(&add_url, payload)
             .await
             .context("add to deck")?;
         if !res.status().is_success() {
@@ -201,21 +932,13 @@ impl VocabCard<'_> {
         Ok(())
     }
 
-    async fn set_custom_sentence(
-        &self,
-        service: &mut BufferedService,
-        sentence: &str,
-    ) -> Result<()> {
-        debug!("custom sentence: {}", sentence);
-        if sentence.len() < 1 {
-            info!("Sentence field was empty. Will not set custom sentence.");
-            return Ok(());
-        }
-        let vocab_id = self.find_id()?;
+    async fn set_custom_sentence(&mut self, vocab_id: &VocabId, sentence: &str) -> Result<()> {
         let VocabId { v, s, r } = vocab_id;
         let edit_sentence_url = format!("/edit-shown-sentence?v={}&s={}&r={}", v, s, r);
         let payload: [(&str, &str); 2] = [("sentence", sentence), ("translation", "")];
-        let res = form_request(service, &edit_sentence_url, payload)
+        let res = self
+            .site
+            .form_request(&edit_sentence_url, payload)
             .await
             .context("set custom sentence request")?;
         let status = res.status();
@@ -229,20 +952,10 @@ impl VocabCard<'_> {
         Ok(())
     }
 
-    async fn set_custom_definition(
-        &self,
-        service: &mut BufferedService,
-        definition: &str,
-    ) -> Result<()> {
+    async fn set_custom_definition(&mut self, vocab_id: &VocabId, definition: &str) -> Result<()> {
         // TODO: Add option to retain original definitions
         // The api overwrites the full list of shown definitions
         // Doing this would require fetching the list first
-        debug!("custom definition: {}", definition);
-        if definition.len() < 1 {
-            info!("Definition field was empty. Will not update definitions.");
-            return Ok(());
-        }
-        let vocab_id = self.find_id()?;
         let VocabId { v, s, r } = vocab_id;
         let edit_definition_url = format!("/edit_shown_meanings?v={}&s={}&r={}", v, s, r);
         // TODO:
@@ -260,7 +973,9 @@ impl VocabCard<'_> {
             ("language-hungarian", "1"),
             ("custom-definition", definition),
         ];
-        let res = form_request(service, &edit_definition_url, payload)
+        let res = self
+            .site
+            .form_request(&edit_definition_url, payload)
             .await
             .context("set custom definition request")?;
         let status = res.status();
@@ -274,11 +989,12 @@ impl VocabCard<'_> {
         Ok(())
     }
 
-    async fn forq(&self, service: &mut BufferedService, origin: &str) -> Result<()> {
-        let vocab_id = self.find_id()?;
+    async fn forq(&mut self, vocab_id: &VocabId, origin: &str) -> Result<()> {
         let payload: [(&str, &str); 3] =
             [("v", &vocab_id.v), ("s", &vocab_id.s), ("origin", origin)];
-        let res = form_request(service, "/prioritize", payload)
+        let res = self
+            .site
+            .form_request("/prioritize", payload)
             .await
             .context("forq request")?;
         let status = res.status();
@@ -289,11 +1005,12 @@ impl VocabCard<'_> {
         Ok(())
     }
 
-    async fn force_unlock(&self, service: &mut BufferedService, origin: &str) -> Result<()> {
-        let vocab_id = self.find_id()?;
+    async fn force_unlock(&mut self, vocab_id: &VocabId, origin: &str) -> Result<()> {
         let payload: [(&str, &str); 3] =
             [("v", &vocab_id.v), ("s", &vocab_id.s), ("origin", origin)];
-        let res = form_request(service, "/force-unlock", payload)
+        let res = self
+            .site
+            .form_request("/force-unlock", payload)
             .await
             .context("force-unlock request")?;
         let status = res.status();
@@ -304,11 +1021,12 @@ impl VocabCard<'_> {
         Ok(())
     }
 
-    async fn mark_unknown(&self, service: &mut BufferedService, origin: &str) -> Result<()> {
-        let vocab_id = self.find_id()?;
+    async fn mark_unknown(&mut self, vocab_id: &VocabId, origin: &str) -> Result<()> {
         let payload: [(&str, &str); 3] =
             [("v", &vocab_id.v), ("s", &vocab_id.s), ("origin", origin)];
-        let res = form_request(service, "/mark-as-not-known", payload)
+        let res = self
+            .site
+            .form_request("/mark-as-not-known", payload)
             .await
             .context("force-unlock request")?;
         let status = res.status();
@@ -0,0 +1,196 @@
+//! data kept in a json file next to the config, like the lookup cache and the queue
+use anyhow::Result;
+use log::*;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+struct Inner<T> {
+    /// None keeps the data in memory only
+    path: Option<PathBuf>,
+    data: T,
+    /// changed since the last save
+    dirty: bool,
+    /// a save is running, it writes later changes too
+    saving: bool,
+}
+
+/// shared by all clones, changes are written in the background and changes in quick
+/// succession are written together
+pub struct JsonFile<T> {
+    /// for log messages, e.g. `queue`
+    what: &'static str,
+    inner: Arc<Mutex<Inner<T>>>,
+}
+
+impl<T> Clone for JsonFile<T> {
+    fn clone(&self) -> Self {
+        JsonFile {
+            what: self.what,
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+/// unix time in seconds
+pub fn now() -> u64 {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH);
+    since_epoch.map(|d| d.as_secs()).unwrap_or_default()
+}
+
+/// `path` with `suffix` appended, `queue.json` -> `queue.json.tmp`
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    name.into()
+}
+
+impl<T: Serialize + DeserializeOwned + Default + Send + 'static> JsonFile<T> {
+    /// reads the file if there is one
+    /// a broken file is moved aside instead of being overwritten by the next change
+    pub fn load(what: &'static str, path: Option<PathBuf>) -> Self {
+        let content = path.as_ref().map(std::fs::read_to_string);
+        let data = match (&path, content) {
+            (Some(path), Some(Ok(content))) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                let aside = with_suffix(path, &format!(".broken-{}", now()));
+                match std::fs::rename(path, &aside) {
+                    Ok(()) => error!(
+                        "The {} file is broken, it was moved to {}: {}",
+                        what,
+                        aside.display(),
+                        e
+                    ),
+                    Err(rename) => error!(
+                        "The {} file is broken and can't be moved aside: {}, {}",
+                        what, e, rename
+                    ),
+                }
+                T::default()
+            }),
+            _ => T::default(),
+        };
+        JsonFile {
+            what,
+            inner: Arc::new(Mutex::new(Inner {
+                path,
+                data,
+                dirty: false,
+                saving: false,
+            })),
+        }
+    }
+
+    /// looks at the data without saving it
+    pub fn read<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        // nothing panics while holding the lock
+        f(&self.inner.lock().unwrap().data)
+    }
+
+    /// changes the data and saves it
+    pub fn update<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let mut inner = self.inner.lock().unwrap();
+        let result = f(&mut inner.data);
+        if inner.path.is_none() {
+            return result;
+        }
+        inner.dirty = true;
+        if inner.saving {
+            return result;
+        }
+        inner.saving = true;
+        drop(inner);
+        let file = self.clone();
+        match tokio::runtime::Handle::try_current() {
+            // the file is written without holding up the requests
+            Ok(runtime) => {
+                runtime.spawn_blocking(move || file.save_changes());
+            }
+            Err(_) => file.save_changes(),
+        }
+        result
+    }
+
+    /// saves until there are no changes left, the lock is only held to serialize the data
+    fn save_changes(&self) {
+        loop {
+            let mut inner = self.inner.lock().unwrap();
+            if !inner.dirty {
+                inner.saving = false;
+                return;
+            }
+            inner.dirty = false;
+            let json = serde_json::to_string(&inner.data);
+            let path = inner.path.clone();
+            drop(inner);
+            let saved = json.map_err(Into::into).and_then(|json| save(path, json));
+            if let Err(e) = saved {
+                error!("Can't save the {}: {:#}", self.what, e);
+            }
+        }
+    }
+}
+
+/// through a temporary file, so a crash can't leave half of it behind
+fn save(path: Option<PathBuf>, json: String) -> Result<()> {
+    let Some(path) = path else {
+        return Ok(());
+    };
+    let tmp = with_suffix(&path, ".tmp");
+    std::fs::write(&tmp, json)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_file_test() {
+        let dir = std::env::temp_dir().join(format!("jpdb_connect_json_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("data.json");
+        let file: JsonFile<Vec<u32>> = JsonFile::load("test data", Some(path.clone()));
+        file.update(|data| data.push(1));
+        assert_eq!(
+            vec![1],
+            JsonFile::<Vec<u32>>::load("test data", Some(path.clone())).read(Vec::clone)
+        );
+
+        // a half written file is kept for the user to look at
+        std::fs::write(&path, "[1, 2").unwrap();
+        let broken: JsonFile<Vec<u32>> = JsonFile::load("test data", Some(path.clone()));
+        assert!(broken.read(Vec::is_empty));
+        assert!(!path.exists());
+        let names: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert!(names
+            .iter()
+            .any(|n| n.to_string_lossy().starts_with("data.json.broken-")));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn background_save_test() {
+        let dir = std::env::temp_dir().join(format!("jpdb_connect_bg_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("data.json");
+        let file: JsonFile<Vec<u32>> = JsonFile::load("test data", Some(path.clone()));
+        for i in 0..100 {
+            file.update(|data| data.push(i));
+        }
+        while file.inner.lock().unwrap().saving {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(!content.contains('\n'), "should be compact");
+        let loaded: Vec<u32> = serde_json::from_str(&content).unwrap();
+        assert_eq!((0..100).collect::<Vec<_>>(), loaded);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,163 @@
+//! remembers which card a word is, so mining or checking it again needs no search
+use crate::backend::Card;
+use crate::json_file::{now, JsonFile};
+use crate::parsing::VocabId;
+use log::*;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub const FILE_NAME: &str = "jpdb_connect_cache.json";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Entry {
+    word: String,
+    reading: Option<String>,
+    detail_url: String,
+    id: Option<VocabId>,
+    /// unix time in seconds
+    added: u64,
+}
+
+type Key = (String, Option<String>);
+
+/// saved as a list, json objects can't have tuples as keys
+#[derive(Default)]
+struct Entries(HashMap<Key, Entry>);
+
+impl Serialize for Entries {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.values())
+    }
+}
+
+impl<'de> Deserialize<'de> for Entries {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let entries = Vec::<Entry>::deserialize(deserializer)?;
+        let entries = entries
+            .into_iter()
+            .map(|entry| (key(&entry.word, entry.reading.as_deref()), entry));
+        Ok(Entries(entries.collect()))
+    }
+}
+
+/// (word, reading) -> card, shared by all clones
+/// the default does nothing, so a missing cache file is never a problem
+#[derive(Clone, Default)]
+pub struct LookupCache {
+    inner: Option<(JsonFile<Entries>, Duration)>,
+}
+
+impl LookupCache {
+    /// reads the cache file if there is one, expired entries are dropped
+    pub fn load(path: Option<PathBuf>, ttl: Duration) -> Self {
+        let entries: JsonFile<Entries> = JsonFile::load("lookup cache", path);
+        let count = entries.read(|entries| entries.0.len());
+        debug!("{} words in the lookup cache", count);
+        let cache = LookupCache {
+            inner: Some((entries, ttl)),
+        };
+        cache.with(|entries, ttl| entries.0.retain(|_, entry| !expired(entry, ttl)));
+        cache
+    }
+
+    fn with<T>(&self, f: impl FnOnce(&mut Entries, Duration) -> T) -> Option<T> {
+        let (entries, ttl) = self.inner.as_ref()?;
+        Some(entries.update(|entries| f(entries, *ttl)))
+    }
+
+    pub fn get(&self, word: &str, reading: Option<&str>) -> Option<Card> {
+        let (entries, ttl) = self.inner.as_ref()?;
+        entries.read(|entries| {
+            let entry = entries.0.get(&key(word, reading))?;
+            if expired(entry, *ttl) {
+                return None;
+            }
+            Some(Card {
+                detail_url: entry.detail_url.clone(),
+                id: entry.id.clone(),
+            })
+        })
+    }
+
+    pub fn insert(&self, word: &str, reading: Option<&str>, card: &Card) {
+        self.with(|entries, _| {
+            let entry = Entry {
+                word: word.into(),
+                reading: reading.map(String::from),
+                detail_url: card.detail_url.clone(),
+                id: card.id.clone(),
+                added: now(),
+            };
+            entries.0.insert(key(word, reading), entry);
+        });
+    }
+
+    /// forgets the word, e.g. because jpdb didn't accept the cached card
+    pub fn remove(&self, word: &str, reading: Option<&str>) {
+        self.with(|entries, _| {
+            if entries.0.remove(&key(word, reading)).is_some() {
+                debug!("Removed {} from the lookup cache", word);
+            }
+        });
+    }
+}
+
+fn key(word: &str, reading: Option<&str>) -> Key {
+    (word.into(), reading.map(String::from))
+}
+
+fn expired(entry: &Entry, ttl: Duration) -> bool {
+    now().saturating_sub(entry.added) > ttl.as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn card() -> Card {
+        Card {
+            detail_url: "/vocabulary/1259620/見事/みごと".into(),
+            id: Some(VocabId {
+                v: "1259620".into(),
+                s: "1406264136".into(),
+                r: "1437918808".into(),
+            }),
+        }
+    }
+
+    #[test]
+    fn lookup_cache_test() {
+        let path = std::env::temp_dir().join(format!("jpdb_connect_test_{}", std::process::id()));
+        let cache = LookupCache::load(Some(path.clone()), DAY);
+        assert!(cache.get("見事", Some("みごと")).is_none());
+        cache.insert("見事", Some("みごと"), &card());
+        cache.insert("てっきり", None, &card());
+        cache.remove("てっきり", None);
+
+        let loaded = LookupCache::load(Some(path.clone()), DAY);
+        std::fs::remove_file(path).unwrap();
+        let found = loaded.get("見事", Some("みごと")).unwrap();
+        assert_eq!(card().detail_url, found.detail_url);
+        assert_eq!(card().id, found.id);
+        assert!(loaded.get("見事", None).is_none());
+        assert!(loaded.get("てっきり", None).is_none());
+    }
+
+    #[test]
+    fn expired_test() {
+        let cache = LookupCache::load(None, DAY);
+        cache.insert("見事", None, &card());
+        cache.with(|entries, _| {
+            entries
+                .0
+                .values_mut()
+                .for_each(|e| e.added -= 2 * DAY.as_secs())
+        });
+        assert!(cache.get("見事", None).is_none());
+        assert!(LookupCache::default().get("見事", None).is_none());
+    }
+}
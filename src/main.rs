@@ -1,26 +1,46 @@
 mod anki_connect;
+mod api;
+mod backend;
 mod jpdb;
+mod json_file;
+mod lookup_cache;
+#[cfg(test)]
+mod mock;
+mod normalize;
 mod parsing;
+mod profile;
+mod query;
+mod queue;
+mod retry;
+mod secret;
 
+use std::collections::{BTreeMap, HashMap};
 use std::net::IpAddr;
-use std::str::FromStr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
-use tower::ServiceBuilder;
 
-use crate::anki_connect::{AnkiConnectAction, Response};
+use crate::anki_connect::{AnkiConnectAction, NoteInfo, Response, FIELD_NAMES};
+use crate::backend::BackendKind;
 use crate::jpdb::*;
-use crate::parsing::has_login_prompt;
-use anyhow::{Context, Result};
+use crate::lookup_cache::LookupCache;
+use crate::parsing::{has_login_prompt, Deck};
+use crate::profile::{ProfileConfigs, Profiles, SharedProfiles};
+use crate::query::{parse_query, Term};
+use crate::queue::MiningQueue;
+use crate::secret::Secret;
+use anyhow::{bail, Context, Result};
+use clap::Parser;
 use log::*;
-use reqwest::cookie::Jar;
 use tokio::sync::Mutex;
 use warp::hyper::body::Bytes;
+use warp::path::FullPath;
 use warp::Filter;
 
 #[derive(Clone, Debug, serde::Deserialize)]
 pub struct Config {
-    pub session_id: Option<String>,
+    pub session_id: Option<Secret>,
+    pub session_id_file: Option<PathBuf>,
     #[serde(default)]
     pub auto_open: bool,
     pub auto_add: Option<u64>,
@@ -36,48 +56,287 @@ pub struct Config {
     #[serde(default)]
     pub add_custom_definition: bool,
     pub port: Option<u16>,
-    pub ip: Option<String>,
+    pub ip: Option<IpAddr>,
+    pub api_key: Option<Secret>,
+    pub api_key_file: Option<PathBuf>,
+    pub jpdb_api_key: Option<Secret>,
+    pub jpdb_api_key_file: Option<PathBuf>,
+    pub backend: Option<BackendKind>,
+    pub base_url: Option<String>,
+    pub lookup_cache_days: Option<u64>,
+    pub rate_limit_requests: Option<u64>,
+    pub rate_limit_seconds: Option<u64>,
+    pub concurrency: Option<usize>,
+    pub buffer_size: Option<usize>,
+    /// yomichan decks which pick this profile
+    #[serde(default)]
+    pub deck_names: Vec<String>,
+}
+
+/// how hard jpdb-connect may hit jpdb
+#[derive(Debug, PartialEq, Eq)]
+pub struct RequestLimits {
+    /// at most `requests` every `per`
+    pub requests: u64,
+    pub per: Duration,
+    /// requests sent at the same time
+    pub concurrency: usize,
+    /// requests which can wait for their turn
+    pub buffer_size: usize,
 }
 
 impl Config {
+    /// the jpdb instance to talk to, without a trailing slash
+    fn base_url(&self) -> &str {
+        self.base_url
+            .as_deref()
+            .unwrap_or(DEFAULT_BASE_URL)
+            .trim_end_matches('/')
+    }
+
+    /// the limits from the config, settings which are likely to get the IP banned are errors
+    fn request_limits(&self) -> Result<RequestLimits> {
+        let limits = RequestLimits {
+            requests: self.rate_limit_requests.unwrap_or(5),
+            per: Duration::from_secs(self.rate_limit_seconds.unwrap_or(3)),
+            concurrency: self.concurrency.unwrap_or(1),
+            buffer_size: self.buffer_size.unwrap_or(100),
+        };
+        if limits.requests == 0 || limits.per.is_zero() {
+            bail!("rate_limit_requests and rate_limit_seconds have to be at least 1");
+        }
+        // 5 requests every 3 seconds is known to be fine, u128 so huge values can't overflow
+        if u128::from(limits.requests) * 3 > u128::from(limits.per.as_secs()) * 5 {
+            bail!(
+                "{} requests every {} seconds would likely get your IP banned by jpdb, \
+                 the most allowed is 5 every 3 seconds",
+                limits.requests,
+                limits.per.as_secs()
+            );
+        }
+        if !(1..=3).contains(&limits.concurrency) {
+            bail!("concurrency has to be between 1 and 3");
+        }
+        if limits.buffer_size == 0 {
+            bail!("buffer_size has to be at least 1");
+        }
+        Ok(limits)
+    }
+
+    fn secrets(&self) -> impl Iterator<Item = &Secret> {
+        [&self.session_id, &self.api_key, &self.jpdb_api_key]
+            .into_iter()
+            .flatten()
+    }
+
+    /// secrets from env vars and files take precedence over the ones in the config file
+    fn read_secrets(&mut self, prefix: &str, env: secret::Env) -> Result<()> {
+        self.session_id = secret::resolve(
+            env,
+            &format!("{prefix}_SESSION_ID"),
+            self.session_id_file.as_deref(),
+            self.session_id.take(),
+        )?;
+        self.api_key = secret::resolve(
+            env,
+            &format!("{prefix}_API_KEY"),
+            self.api_key_file.as_deref(),
+            self.api_key.take(),
+        )?;
+        self.jpdb_api_key = secret::resolve(
+            env,
+            &format!("{prefix}_JPDB_API_KEY"),
+            self.jpdb_api_key_file.as_deref(),
+            self.jpdb_api_key.take(),
+        )?;
+        Ok(())
+    }
+
+    /// what adding a note does to the card
+    pub fn mining_options(&self) -> MiningOptions {
+        MiningOptions {
+            auto_add: self.auto_add,
+            auto_forq: self.auto_forq,
+            auto_unlock: self.auto_unlock,
+            auto_forget: self.auto_forget,
+            add_mined_sentences: self.add_mined_sentences,
+            add_custom_definition: self.add_custom_definition,
+        }
+    }
+
+    /// the jpdb api can't unlock, forget or set definitions, those options need a session_id
+    fn check_api_options(&self) -> Result<()> {
+        if self.jpdb_api_key.is_none() || self.session_id.is_some() {
+            return Ok(());
+        }
+        let website_only = [
+            ("auto_unlock", self.auto_unlock),
+            ("auto_forget", self.auto_forget),
+            ("add_custom_definition", self.add_custom_definition),
+        ];
+        if let Some((option, _)) = website_only.iter().find(|(_, enabled)| *enabled) {
+            bail!("the jpdb api can't do {option}, it needs a session_id as well");
+        }
+        Ok(())
+    }
+
     /// true if any options that need the user to be logged in and to access the detail page
     /// are enabled
     fn any_login_or_detail_options(&self) -> bool {
-        self.auto_add.is_some()
-            || self.auto_forq
-            || self.auto_unlock
-            || self.auto_forget
-            || self.add_mined_sentences
-            || self.add_custom_definition
+        self.mining_options().any_login_or_detail_options()
     }
 }
 
+#[derive(Default)]
 pub struct Cache {
     last_open: Option<String>,
+    /// note id -> url of every card added while running
+    opened: HashMap<i64, String>,
 }
 
-fn read_config() -> Result<Config> {
-    let exe_path = std::env::current_exe()?;
-    let config_path = exe_path
-        .parent()
-        .context("no parent T_T")?
-        .join("jpdb_connect.toml");
+const CONFIG_FILE_NAME: &str = "jpdb_connect.toml";
 
-    let content = if config_path.as_path().exists() {
-        println!("loading config from {}", config_path.display());
-        std::fs::read_to_string(&config_path)?
-    } else {
-        println!("creating default config file at {}", config_path.display());
-        let s = include_str!("default_config.toml");
-        std::fs::write(&config_path, s)?;
-        s.to_string()
+const DEFAULT_CONFIG: &str = include_str!("default_config.toml");
+
+/// command line options
+#[derive(clap::Parser, Clone, Debug, Default)]
+#[command(
+    version,
+    about = "Lets yomichan mine words to jpdb by pretending to be AnkiConnect"
+)]
+struct Args {
+    /// config file to use instead of looking for jpdb_connect.toml
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// port to listen on, takes precedence over the config file
+    #[arg(long)]
+    port: Option<u16>,
+    /// ip to listen on, takes precedence over the config file
+    #[arg(long)]
+    ip: Option<IpAddr>,
+    /// directory for the lookup cache and the queue instead of the one of the config file
+    #[arg(long, value_name = "PATH")]
+    data_dir: Option<PathBuf>,
+    /// print the default config and exit
+    #[arg(long)]
+    print_default_config: bool,
+}
+
+/// e.g. ~/.config/jpdb_connect/jpdb_connect.toml
+fn user_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("jpdb_connect").join(CONFIG_FILE_NAME))
+}
+
+/// where jpdb_connect.toml is looked for, the first one which exists is used
+/// older versions created it next to the executable
+fn config_candidates() -> Vec<PathBuf> {
+    let mut candidates: Vec<PathBuf> = user_config_path().into_iter().collect();
+    let exe_dir = std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(PathBuf::from));
+    if let Some(dir) = exe_dir {
+        candidates.push(dir.join(CONFIG_FILE_NAME));
+    }
+    candidates
+}
+
+/// the config file to use, None if there is none and it can't be created
+fn find_config(args: &Args) -> Result<Option<PathBuf>> {
+    if let Some(ref path) = args.config {
+        if !path.exists() {
+            bail!("{} does not exist", path.display());
+        }
+        return Ok(Some(path.clone()));
+    }
+    let candidates = config_candidates();
+    if let Some(path) = candidates.iter().find(|path| path.exists()) {
+        return Ok(Some(path.clone()));
+    }
+    // never next to the executable, it may be read-only
+    let Some(path) = user_config_path() else {
+        println!("no config directory found, using the default config");
+        return Ok(None);
     };
-    Ok(toml::from_str(&content)?)
+    let dir = path.parent().context("no parent T_T")?;
+    match std::fs::create_dir_all(dir).and_then(|_| std::fs::write(&path, DEFAULT_CONFIG)) {
+        Ok(()) => {
+            println!("creating default config file at {}", path.display());
+            Ok(Some(path))
+        }
+        Err(e) => {
+            println!(
+                "can't create {}, using the default config: {}",
+                path.display(),
+                e
+            );
+            Ok(None)
+        }
+    }
+}
+
+/// where the lookup cache and the queue are saved, None if nothing should be written
+/// usually next to the config, a read-only one like in the nix store uses the data directory
+fn find_data_dir(args: &Args, config: Option<&Path>) -> Result<Option<PathBuf>> {
+    if let Some(ref dir) = args.data_dir {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("can't create data directory {}", dir.display()))?;
+        return Ok(Some(dir.clone()));
+    }
+    // without a config file nothing is written to disk
+    let Some(config_dir) = config.and_then(Path::parent) else {
+        return Ok(None);
+    };
+    if is_writable(config_dir) {
+        return Ok(Some(config_dir.to_path_buf()));
+    }
+    let Some(dir) = dirs::data_dir().map(|dir| dir.join("jpdb_connect")) else {
+        return Ok(None);
+    };
+    match std::fs::create_dir_all(&dir) {
+        Ok(()) => Ok(Some(dir)),
+        Err(e) => {
+            warn!("Can't create {}: {}", dir.display(), e);
+            Ok(None)
+        }
+    }
+}
+
+/// by trying it, the permissions don't tell about read-only file systems
+fn is_writable(dir: &Path) -> bool {
+    let probe = dir.join(".jpdb_connect_write_test");
+    let writable = std::fs::write(&probe, "").is_ok();
+    let _ = std::fs::remove_file(&probe);
+    writable
+}
+
+/// reads the config file, the command line overrides are applied
+fn load_config(path: &Path, args: &Args) -> Result<ProfileConfigs> {
+    let content = std::fs::read_to_string(path)?;
+    parse_config(&content, args)
+}
+
+fn parse_config(content: &str, args: &Args) -> Result<ProfileConfigs> {
+    let mut configs = ProfileConfigs::parse(content)?;
+    if args.port.is_some() {
+        configs.default.port = args.port;
+    }
+    if args.ip.is_some() {
+        configs.default.ip = args.ip;
+    }
+    configs.read_secrets("JPDB_CONNECT", &secret::process_env)?;
+    Ok(configs)
 }
 
-async fn validate_config(config: &Config, client: &reqwest::Client) -> Result<()> {
-    let should_auto_add = config.session_id.is_some() && config.auto_add.is_some();
+async fn validate_config(config: &Config, site: &mut Site) -> Result<()> {
+    reqwest::Url::parse(config.base_url())
+        .with_context(|| format!("base_url '{}' is not a valid url", config.base_url()))?;
+    config.check_api_options()?;
+    let logged_in = config.session_id.is_some() || config.jpdb_api_key.is_some();
+    let should_auto_add = logged_in && config.auto_add.is_some();
 
+    if config.base_url() != DEFAULT_BASE_URL {
+        info!("Using jpdb at {}", config.base_url());
+    }
     info!("Auto open card in browser: {}", config.auto_open);
     info!("Auto add card to deck: {}", should_auto_add);
     info!("Auto FORQ: {}", config.auto_forq);
@@ -86,19 +345,26 @@ async fn validate_config(config: &Config, client: &reqwest::Client) -> Result<()
     info!("Add mined sentences: {}", config.add_mined_sentences);
     info!("Add custom definition: {}", config.add_custom_definition);
 
+    let public_ip = config.ip.filter(|ip| !ip.is_loopback());
+    if let (Some(ip), None) = (public_ip, &config.api_key) {
+        warn!(
+            "Everyone who can reach {} can use your jpdb account. Set an api_key to prevent that.",
+            ip
+        );
+    }
+
     if !config.auto_open && !config.any_login_or_detail_options() {
         warn!("In this configuration jpdb-connect does not do anything.");
     }
 
     let test_login = config.session_id.is_some() && config.any_login_or_detail_options();
     if test_login {
-        let response = client
-            .get(if let Some(deck_id) = config.auto_add {
-                abs_url(format!("/deck?id={}", deck_id))
+        let response = site
+            .get_request(&if let Some(deck_id) = config.auto_add {
+                format!("/deck?id={}", deck_id)
             } else {
-                abs_url("/")
+                "/".to_string()
             })
-            .send()
             .await?;
 
         let status_code = response.status().as_u16();
@@ -106,7 +372,7 @@ async fn validate_config(config: &Config, client: &reqwest::Client) -> Result<()
             .text()
             .await
             .unwrap_or("Some error happened.".into());
-        let has_login_prompt = has_login_prompt(&body);
+        let has_login_prompt = has_login_prompt(body);
         debug!("has_login_prompt {}", has_login_prompt);
         trace!("Status code: {}", status_code);
         trace!("Body: {}", body);
@@ -122,9 +388,21 @@ async fn validate_config(config: &Config, client: &reqwest::Client) -> Result<()
     Ok(())
 }
 
+async fn validate_jpdb_api_key(jpdb: &mut JPDBConnection) {
+    info!("Use jpdb api: {}", jpdb.config.jpdb_api_key.is_some());
+    if let Some(token) = jpdb.config.jpdb_api_key.clone() {
+        match api::ping(&mut jpdb.site, token.expose()).await {
+            Ok(()) => info!("jpdb api key works."),
+            Err(e) => error!("Your jpdb_api_key doesn't work: {:#}", e),
+        }
+    }
+}
+
 fn setup_logger(config: &Config) -> Result<()> {
     fern::Dispatch::new()
         .format(|out, message, record| {
+            // urls, cookies and requests from other crates can contain them too
+            let message = secret::redact(&message.to_string());
             out.finish(format_args!(
                 "{}[{}][{}] {}",
                 chrono::Local::now().format("[%H:%M:%S]"),
@@ -146,61 +424,43 @@ fn setup_logger(config: &Config) -> Result<()> {
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let config = read_config().context("Config file can not be loaded.")?;
-    setup_logger(&config)?;
-    let port = config.port.unwrap_or(3030);
-    let ip = config
-        .ip
-        .as_ref()
-        .and_then(|ip| IpAddr::from_str(&ip).ok())
-        .unwrap_or([127, 0, 0, 1].into());
-
-    let mut client = reqwest::Client::builder();
-    if let Some(ref sid) = config.session_id {
-        let jar = Jar::default();
-        const COOKIE_NAME: &str = "sid";
-        let cookie_str = format!("{COOKIE_NAME}={}; Domain={DOMAIN}", sid);
-        jar.add_cookie_str(&cookie_str, &format!("{URL_PREFIX}{DOMAIN}").parse()?);
-        client = client.cookie_store(true).cookie_provider(jar.into());
-    }
-    let client = client.build()?;
-
-    validate_config(&config, &client).await?;
-
-    let service = ServiceBuilder::new()
-        .buffer(100)
-        .concurrency_limit(1)
-        .rate_limit(5, Duration::from_secs(3)) // so that we don't get IP banned
-        .service(ReqwestService { client });
-
-    let jpdb = JPDBConnection { service, config };
-
-    let cache = Arc::new(Mutex::new(Cache { last_open: None }));
-
-    let bytes = warp::any()
-        .and(warp::body::bytes())
-        .then(move |body: Bytes| {
-            let jpdb = jpdb.clone();
-            let mut cache = cache.clone();
-            async move {
-                let s: String = String::from_utf8(body.slice(..).to_vec()).unwrap();
-                trace!("Request received:");
-                trace!("{}", s);
-                let a: AnkiConnectAction = serde_json::from_str(&s).unwrap();
-
-                let answer = &handle_action(&a, jpdb, &mut cache).await;
-                let r = if a.version == 2 {
-                    answer.version_downgrade()
-                } else {
-                    serde_json::to_string(answer).unwrap()
-                };
-                debug!("Anki-connect answer: '{}'", r);
-                return r;
-            }
-        });
+    let args = Args::parse();
+    if args.print_default_config {
+        print!("{DEFAULT_CONFIG}");
+        return Ok(());
+    }
+    let path = find_config(&args).context("Config file can not be loaded.")?;
+    let configs = match path {
+        Some(ref path) => {
+            println!("loading config from {}", path.display());
+            load_config(path, &args)
+        }
+        None => parse_config(DEFAULT_CONFIG, &args),
+    };
+    let configs = configs.context("Config file can not be loaded.")?;
+    setup_logger(&configs.default)?;
+    let port = configs.default.port.unwrap_or(3030);
+    let ip = configs.default.ip.unwrap_or([127, 0, 0, 1].into());
+
+    let data_dir = find_data_dir(&args, path.as_deref())?;
+    match data_dir {
+        Some(ref dir) => info!(
+            "Saving the lookup cache and queued notes in {}",
+            dir.display()
+        ),
+        None => warn!("The lookup cache and queued notes are not saved."),
+    }
+    let profiles = connect_profiles(configs, data_dir.as_deref(), None).await?;
+    let profiles = Arc::new(std::sync::RwLock::new(profiles));
+    tokio::spawn(replay_queue(profiles.clone()));
+    if let Some(path) = path {
+        tokio::spawn(watch_config(path, args, profiles.clone()));
+    }
+
+    let cache = Arc::new(Mutex::new(Cache::default()));
 
     info!("Starting server.");
-    warp::serve(bytes.with(warp::log::custom(|info| {
+    warp::serve(routes(profiles, cache).with(warp::log::custom(|info| {
         debug!("{} {} {}", info.method(), info.path(), info.status(),);
     })))
     .run((ip, port))
@@ -208,79 +468,576 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+/// validated connections for the top level config and every profile
+/// the lookup cache and the queues are taken over from `old`, otherwise they are loaded
+async fn connect_profiles(
+    configs: ProfileConfigs,
+    data_dir: Option<&Path>,
+    old: Option<&Profiles>,
+) -> Result<Profiles> {
+    let queue = |name: Option<&str>| match old.and_then(|old| old.get(name)) {
+        Some(old) => old.queue.clone(),
+        None => MiningQueue::load(data_dir.map(|dir| dir.join(queue::file_name(name)))),
+    };
+
+    // a new rate limiter would start with a full budget while requests still use the old one
+    let limits = configs.default.request_limits()?;
+    let mut default = match old {
+        Some(old) if old.default.config.request_limits().ok().as_ref() == Some(&limits) => {
+            old.default.sharing(configs.default)?
+        }
+        _ => {
+            // a hanging request would block all others, the retry policy tries GETs again
+            let client = reqwest::Client::builder()
+                .timeout(Duration::from_secs(30))
+                .build()?;
+            JPDBConnection::new(client, configs.default)?
+        }
+    };
+    validate_config(&default.config, &mut default.site).await?;
+    validate_jpdb_api_key(&mut default).await;
+    let days = default.config.lookup_cache_days.unwrap_or(30);
+    default.lookups = match old {
+        Some(old) if old.default.config.lookup_cache_days.unwrap_or(30) == days => {
+            old.default.lookups.clone()
+        }
+        // every change was saved, loading it again only applies the new days
+        _ => match days {
+            0 => LookupCache::default(),
+            _ => LookupCache::load(
+                data_dir.map(|dir| dir.join(lookup_cache::FILE_NAME)),
+                Duration::from_secs(days * 24 * 60 * 60),
+            ),
+        },
+    };
+    default.queue = queue(None);
+
+    let mut named = BTreeMap::new();
+    for (name, config) in configs.named {
+        info!("Profile {}:", name);
+        let mut jpdb = default.sharing(config)?;
+        validate_config(&jpdb.config, &mut jpdb.site).await?;
+        validate_jpdb_api_key(&mut jpdb).await;
+        jpdb.queue = queue(Some(&name));
+        named.insert(name, jpdb);
+    }
+    default.profile_decks = named
+        .values()
+        .flat_map(|jpdb| {
+            let id = jpdb.config.auto_add.unwrap_or_default();
+            let names = jpdb.config.deck_names.iter();
+            names.map(move |name| Deck {
+                id,
+                name: name.clone(),
+            })
+        })
+        .collect();
+    Ok(Profiles { default, named })
+}
+
+/// looks at the config file every few seconds and switches to the new config when it changed
+async fn watch_config(path: PathBuf, args: Args, profiles: SharedProfiles) {
+    let modified = |path: &PathBuf| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut last = modified(&path);
+    let mut interval = tokio::time::interval(Duration::from_secs(2));
+    loop {
+        interval.tick().await;
+        let now = modified(&path);
+        if now == last {
+            continue;
+        }
+        last = now;
+        info!("The config file changed, reloading it.");
+        match reload_config(&path, &args, &profiles).await {
+            Ok(()) => info!("The new config is active."),
+            Err(e) => error!("Keeping the old config: {:#}", e),
+        }
+    }
+}
+
+/// the lookup cache and the queue stay, everything else is set up again
+async fn reload_config(path: &Path, args: &Args, profiles: &SharedProfiles) -> Result<()> {
+    let configs = load_config(path, args)?;
+    let old = profiles.read().unwrap().clone();
+    let (config, old_config) = (&configs.default, &old.default.config);
+    if config.port != old_config.port || config.ip != old_config.ip {
+        warn!("Changes to port and ip need a restart to take effect.");
+    }
+    if config.log_level != old_config.log_level {
+        warn!("Changes to log_level need a restart to take effect.");
+    }
+    let data_dir = find_data_dir(args, Some(path))?;
+    let new = connect_profiles(configs, data_dir.as_deref(), Some(&old)).await?;
+    // secrets which were removed from the config can show up in the logs again
+    let current: Vec<&Secret> = new.all().flat_map(|(_, j)| j.config.secrets()).collect();
+    for (_, jpdb) in old.all() {
+        let removed = jpdb.config.secrets().filter(|s| !current.contains(s));
+        removed.for_each(Secret::forget);
+    }
+    *profiles.write().unwrap() = new;
+    Ok(())
+}
+
+/// adds queued notes in the background, waits longer after every failed try
+async fn replay_queue(shared: SharedProfiles) {
+    const MIN_DELAY: Duration = Duration::from_secs(30);
+    const MAX_DELAY: Duration = Duration::from_secs(30 * 60);
+    let mut delay = MIN_DELAY;
+    loop {
+        tokio::time::sleep(delay).await;
+        let profiles = shared.read().unwrap().clone();
+        let mut replayed = false;
+        let mut failed = false;
+        for (name, jpdb) in profiles.all() {
+            if jpdb.queue.is_empty() {
+                continue;
+            }
+            replayed = true;
+            if let Err(e) = jpdb.clone().replay_queue().await {
+                failed = true;
+                match name {
+                    Some(name) => info!("Profile {}: {:#}", name, e),
+                    None => info!("{:#}", e),
+                }
+            }
+        }
+        if failed {
+            delay = (delay * 2).min(MAX_DELAY);
+            info!("Trying the queue again in {}s", delay.as_secs());
+        } else if replayed {
+            delay = MIN_DELAY;
+        }
+    }
+}
+
+/// every request is an AnkiConnect action, the path can name a profile
+fn routes(
+    profiles: SharedProfiles,
+    cache: Arc<Mutex<Cache>>,
+) -> impl Filter<Extract = (String,), Error = warp::Rejection> + Clone {
+    warp::any()
+        .and(warp::path::full())
+        .and(warp::body::bytes())
+        .then(move |path: FullPath, body: Bytes| {
+            // every request keeps the config it started with
+            let profiles = profiles.read().unwrap().clone();
+            handle_request(body, path.as_str().to_string(), profiles, cache.clone())
+        })
+}
+
+/// decodes the request, bad requests get an error answer instead of a panic
+async fn handle_request(
+    body: Bytes,
+    path: String,
+    profiles: Profiles,
+    mut cache: Arc<Mutex<Cache>>,
+) -> String {
+    let s = match String::from_utf8(body.to_vec()) {
+        Ok(s) => s,
+        Err(e) => return error_answer(format!("request is not valid utf-8: {e}")),
+    };
+    trace!("Request received:");
+    trace!("{}", s);
+    let a: AnkiConnectAction = match serde_json::from_str(&s) {
+        Ok(a) => a,
+        Err(e) => return error_answer(format!("invalid request: {e}")),
+    };
+    let jpdb = match profiles.select(&path, &a) {
+        Ok(jpdb) => jpdb.clone(),
+        Err(e) => return error_answer(e.to_string()),
+    };
+
+    let answer = &if is_authorized(&a, &jpdb.config) {
+        handle_action(&a, jpdb, &mut cache).await
+    } else {
+        warn!("Rejected {} request without valid api key.", a.action);
+        Response::error("valid api key must be provided")
+    };
+    let r = answer.to_json_for(a.version);
+    debug!("Anki-connect answer: '{}'", r);
+    r
+}
+
+fn error_answer(error: String) -> String {
+    warn!("{}", error);
+    Response::error(error).to_json()
+}
+
+/// requestPermission is how clients find out that they need a key, so it always works
+fn is_authorized(action: &AnkiConnectAction, config: &Config) -> bool {
+    match config.api_key {
+        Some(ref api_key) => {
+            action.action == "requestPermission" || action.key.as_deref() == Some(api_key.expose())
+        }
+        None => true,
+    }
+}
+
 async fn handle_action(
     action: &AnkiConnectAction,
-    mut jpdb: JPDBConnection,
+    jpdb: JPDBConnection,
     cache: &mut Arc<Mutex<Cache>>,
 ) -> Response {
     debug!("{}", &action.action);
-    match action.action.as_str() {
+    dispatch_action(action, jpdb, cache)
+        .await
+        .unwrap_or_else(|e| {
+            error!("{} failed: {:#}", action.action, e);
+            debug!("{}", e.backtrace());
+            Response::error(e.to_string())
+        })
+}
+
+async fn dispatch_action(
+    action: &AnkiConnectAction,
+    mut jpdb: JPDBConnection,
+    cache: &mut Arc<Mutex<Cache>>,
+) -> Result<Response> {
+    Ok(match action.action.as_str() {
         "version" => Response::result(6),
-        "deckNames" => Response::result(["jpdb"]),
+        "requestPermission" => Response::result(serde_json::json!({
+            "permission": "granted",
+            "requireApikey": jpdb.config.api_key.is_some(),
+            "version": 6,
+        })),
+        "deckNames" => {
+            let decks = jpdb.decks().await?;
+            Response::result(decks.into_iter().map(|d| d.name).collect::<Vec<_>>())
+        }
+        "deckNamesAndIds" => {
+            let decks = jpdb.decks().await?.into_iter().map(|d| (d.name, d.id));
+            Response::result(decks.collect::<BTreeMap<_, _>>())
+        }
         "modelNames" => Response::result(["jpdb", "Select to refresh"]),
-        "modelFieldNames" => Response::result(["word", "reading", "sentence", "definition"]),
+        "modelFieldNames" => Response::result(FIELD_NAMES),
         "addNote" => {
-            let field = &action
-                .params
-                .as_ref()
-                .unwrap()
-                .note
-                .as_ref()
-                .unwrap()
-                .fields;
-            let result = jpdb.add_note(field).await;
-            {
-                let mut cache = cache.lock().await;
-                cache.last_open = match result {
-                    Ok(ref s) => Some(s.clone()),
-                    Err(_) => None,
+            let note = action.note()?;
+            let result = jpdb.add_note_or_queue(note).await;
+            let mut cache = cache.lock().await;
+            cache.last_open = match result {
+                Ok(ref added) => Some(added.open_url.clone()),
+                Err(_) => None,
+            };
+            let added = result?;
+            cache.opened.insert(added.note_id, added.open_url);
+            Response::result(added.note_id)
+        }
+        "addNotes" => {
+            let notes = action.notes()?;
+            let mut ids = Vec::with_capacity(notes.len());
+            // one after another, the service takes care of the rate limit
+            for note in notes {
+                match jpdb.add_note_or_queue(note).await {
+                    Ok(added) => {
+                        let mut cache = cache.lock().await;
+                        cache.opened.insert(added.note_id, added.open_url.clone());
+                        cache.last_open = Some(added.open_url);
+                        ids.push(Some(added.note_id));
+                    }
+                    Err(e) => {
+                        error!("{} failed for {}: {:#}", action.action, note.fields.word, e);
+                        debug!("{}", e.backtrace());
+                        ids.push(None);
+                    }
                 }
             }
-            result
-                .map(|_| Response::result(1234)) // TODO card id
-                .map_err(|e| {
-                    error!("{}", e.backtrace());
-                    e
-                })
-                .unwrap_or_else(|e| Response::error(e.to_string()))
+            Response::result(ids)
         }
         "guiBrowse" => {
-            let cache = cache.lock().await;
-            if let Some(ref open_url) = cache.last_open {
-                match open::that(open_url)
-                    .map(|_| Response::result(true))
-                    .map_err(|e| {
-                        error!("{}", e);
-                        Response::error(e.to_string())
-                    }) {
-                    Ok(it) => it,
-                    Err(it) => it,
-                }
-            } else {
-                Response::error("Can't open nothing")
+            let query = action.params.as_ref().and_then(|p| p.query.as_ref());
+            let url = browse_url(query, &mut jpdb, cache).await?;
+            info!("Opening: {}", url);
+            open::that(url)?;
+            Response::result(true)
+        }
+        "canAddNotes" => Response::result(jpdb.can_add_notes(action.notes()?).await?),
+        "multi" => {
+            let actions = action.actions()?;
+            let mut results = Vec::with_capacity(actions.len());
+            for sub_action in actions {
+                // recursion in async fns needs an indirection
+                let response = Box::pin(handle_action(sub_action, jpdb.clone(), cache)).await;
+                // every entry is answered in the shape of its own version
+                let json = response.to_json_for(sub_action.version);
+                results.push(serde_json::from_str::<serde_json::Value>(&json)?);
             }
+            Response::result(results)
+        }
+        "findNotes" => {
+            let terms = parse_query(action.query()?)?;
+            let notes = jpdb.find_notes(&terms).await?;
+            Response::result(notes.iter().map(|n| n.note_id).collect::<Vec<_>>())
         }
-        "canAddNotes" => {
-            let _v = action
-                .params
-                .as_ref()
-                .unwrap()
-                .notes
-                .as_ref()
-                .unwrap()
+        "notesInfo" => {
+            let ids = action.note_ids()?;
+            let notes = jpdb.notes_info(ids).await?;
+            // anki answers with an empty object for unknown ids
+            let infos = ids
                 .iter()
-                .map(|_| "true".to_string())
-                .collect::<Vec<String>>()
-                .join(", ");
-            warn!("Turn off duplicate detection or yomichan + jpdb-connect won't work.");
-            //format!(r#"[{}]"#, v)
-            Response::error("duplicate detection")
+                .map(|id| match notes.iter().find(|n| n.note_id == *id) {
+                    Some(n) => serde_json::to_value(NoteInfo::new(n.note_id, &n.fields)),
+                    None => Ok(serde_json::json!({})),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Response::result(infos)
+        }
+        // not part of AnkiConnect, for looking at notes which wait for jpdb
+        "queuedNotes" => Response::result(jpdb.queue.notes()),
+        "flushQueue" => {
+            jpdb.replay_queue().await?;
+            Response::result(true)
         }
         _ => {
-            // multi
-            // findnotes
             warn!("unsupported action {}", action.action);
             Response::error("unsupported action")
         }
+    })
+}
+
+/// jpdb page which shows what a guiBrowse query is looking for
+async fn browse_url(
+    query: Option<&String>,
+    jpdb: &mut JPDBConnection,
+    cache: &Arc<Mutex<Cache>>,
+) -> Result<String> {
+    let Some(query) = query else {
+        return cache
+            .lock()
+            .await
+            .last_open
+            .clone()
+            .context("Can't open nothing");
+    };
+    let terms = parse_query(query)?;
+    if let [Term::NoteIds(ids)] = terms.as_slice() {
+        let cache = cache.lock().await;
+        if let Some(url) = ids.iter().find_map(|id| cache.opened.get(id)) {
+            return Ok(url.clone());
+        }
+    }
+    jpdb.browse_url(&terms).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn action(json: &str) -> AnkiConnectAction {
+        serde_json::from_str(json).unwrap()
+    }
+
+    async fn request(body: &[u8]) -> serde_json::Value {
+        let config = toml::from_str("").unwrap();
+        let jpdb = JPDBConnection::new(reqwest::Client::new(), config).unwrap();
+        let cache = Arc::new(Mutex::new(Cache::default()));
+        let body = Bytes::copy_from_slice(body);
+        let answer = handle_request(body, "/".into(), jpdb.into(), cache).await;
+        serde_json::from_str(&answer).unwrap()
+    }
+
+    fn assert_error(answer: &serde_json::Value, expected: &str) {
+        assert_eq!(serde_json::Value::Null, answer["result"]);
+        let error = answer["error"].as_str().unwrap();
+        assert!(
+            error.contains(expected),
+            "'{error}' should contain '{expected}'"
+        );
+    }
+
+    #[tokio::test]
+    async fn undecodable_request_test() {
+        assert_error(&request(b"\xff\xfe").await, "utf-8");
+        assert_error(&request(b"").await, "invalid request");
+        assert_error(&request(b"{\"action\": ").await, "invalid request");
+        assert_error(
+            &request(br#"{"version": 6}"#).await,
+            "missing field `action`",
+        );
+        let partial_note = r#"{"action": "addNote", "version": 6,
+            "params": {"note": {"fields": {"word": "見事"}}}}"#;
+        assert_error(&request(partial_note.as_bytes()).await, "invalid request");
+    }
+
+    #[tokio::test]
+    async fn missing_params_test() {
+        let no_params = br#"{"action": "addNote", "version": 6}"#;
+        assert_error(&request(no_params).await, "missing params");
+        let no_note = br#"{"action": "addNote", "version": 6, "params": {}}"#;
+        assert_error(&request(no_note).await, "missing parameter note");
+        let ids_instead_of_notes =
+            br#"{"action": "canAddNotes", "version": 6, "params": {"notes": [1, 2]}}"#;
+        assert_error(&request(ids_instead_of_notes).await, "list of notes");
+        let no_query = br#"{"action": "findNotes", "version": 6, "params": {}}"#;
+        assert_error(&request(no_query).await, "missing parameter query");
+        let no_actions = br#"{"action": "multi", "version": 6, "params": {}}"#;
+        assert_error(&request(no_actions).await, "missing parameter actions");
+    }
+
+    #[tokio::test]
+    async fn multi_test() {
+        let body = br#"{"action": "multi", "version": 6, "params": {"actions": [
+            {"action": "version", "version": 6},
+            {"action": "deckNames", "version": 6},
+            {"action": "modelFieldNames", "version": 5, "params": {"modelName": "jpdb"}},
+            {"action": "version", "version": 4},
+            {"action": "deckNames"}
+        ]}}"#;
+        let answer = request(body).await;
+        let expected = serde_json::json!({
+            "result": [
+                {"result": 6, "error": null},
+                // there is no auto_add deck
+                {"result": [], "error": null},
+                {"result": FIELD_NAMES, "error": null},
+                // like AnkiConnect, older and missing versions get the bare result
+                6,
+                [],
+            ],
+            "error": null,
+        });
+        assert_eq!(expected, answer);
+    }
+
+    #[tokio::test]
+    async fn missing_params_in_multi_test() {
+        let body = br#"{"action": "multi", "version": 6, "params": {"actions": [
+            {"action": "version"},
+            {"action": "notesInfo", "params": {}}
+        ]}}"#;
+        let answer = request(body).await;
+        assert_eq!(6, answer["result"][0]);
+        assert_error(&answer["result"][1], "missing parameter notes");
+    }
+
+    #[tokio::test]
+    async fn version_2_error_test() {
+        let body = br#"{"action": "addNote", "version": 2, "params": {}}"#;
+        assert_error(&request(body).await, "missing parameter note");
+    }
+
+    #[test]
+    fn request_limits_test() {
+        let limits = |toml: &str| toml::from_str::<Config>(toml).unwrap().request_limits();
+        let default = limits("").unwrap();
+        assert_eq!(5, default.requests);
+        assert_eq!(Duration::from_secs(3), default.per);
+        assert_eq!(1, default.concurrency);
+        assert_eq!(100, default.buffer_size);
+        assert!(limits("rate_limit_requests = 10\nrate_limit_seconds = 6").is_ok());
+        assert!(limits("rate_limit_requests = 10").is_err());
+        let huge = format!("rate_limit_requests = {}\nrate_limit_seconds = 1", i64::MAX);
+        assert!(limits(&huge).is_err());
+        let slow = format!("rate_limit_requests = 1\nrate_limit_seconds = {}", i64::MAX);
+        assert!(limits(&slow).is_ok());
+        assert!(limits("rate_limit_seconds = 0").is_err());
+        assert!(limits("concurrency = 8").is_err());
+        assert!(limits("buffer_size = 0").is_err());
+    }
+
+    #[test]
+    fn base_url_test() {
+        let config: Config = toml::from_str("").unwrap();
+        assert_eq!("https://jpdb.io", config.base_url());
+        let config: Config = toml::from_str(r#"base_url = "http://localhost:8080/""#).unwrap();
+        assert_eq!("http://localhost:8080", config.base_url());
+    }
+
+    #[test]
+    fn load_config_test() {
+        let path =
+            std::env::temp_dir().join(format!("jpdb_connect_args_{}.toml", std::process::id()));
+        std::fs::write(&path, "port = 3030\nip = \"127.0.0.1\"\nauto_add = 3").unwrap();
+        let args = Args::try_parse_from(["jpdb_connect", "--port", "8765"]).unwrap();
+        let config = load_config(&path, &args).unwrap();
+        assert_eq!(Some(8765), config.default.port);
+        assert_eq!(Some([127, 0, 0, 1].into()), config.default.ip);
+        assert_eq!(Some(3), config.default.auto_add);
+
+        let args = Args::try_parse_from(["jpdb_connect", "--config", path.to_str().unwrap()]);
+        assert_eq!(Some(path.clone()), find_config(&args.unwrap()).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        let args = Args::try_parse_from(["jpdb_connect", "--config", path.to_str().unwrap()]);
+        assert!(find_config(&args.unwrap()).is_err());
+        assert!(Args::try_parse_from(["jpdb_connect", "--ip", "localhost"]).is_err());
+        assert!(parse_config("ip = \"localhost\"", &Args::default()).is_err());
+        // the default config has to stay valid
+        toml::from_str::<Config>(DEFAULT_CONFIG).unwrap();
+    }
+
+    #[test]
+    fn check_api_options_test() {
+        let config = |toml: &str| toml::from_str::<Config>(toml).unwrap().check_api_options();
+        config("jpdb_api_key = \"key\"\nauto_add = 3").unwrap();
+        let err = config("jpdb_api_key = \"key\"\nauto_forget = true").unwrap_err();
+        assert!(err.to_string().contains("auto_forget"));
+        config("jpdb_api_key = \"key\"\nsession_id = \"sid\"\nauto_forget = true").unwrap();
+        config("auto_unlock = true").unwrap();
+    }
+
+    #[test]
+    fn find_data_dir_test() {
+        let dir = std::env::temp_dir().join(format!("jpdb_connect_data_{}", std::process::id()));
+        let config = dir.join("jpdb_connect.toml");
+        std::fs::create_dir_all(&dir).unwrap();
+        let found = find_data_dir(&Args::default(), Some(&config)).unwrap();
+        assert_eq!(Some(dir.clone()), found);
+        assert_eq!(None, find_data_dir(&Args::default(), None).unwrap());
+
+        let data_dir = dir.join("data");
+        let args = Args::try_parse_from(["jpdb_connect", "--data-dir", data_dir.to_str().unwrap()]);
+        let found = find_data_dir(&args.unwrap(), Some(&config)).unwrap();
+        assert_eq!(Some(data_dir.clone()), found);
+        assert!(data_dir.is_dir());
+        assert_eq!(1, std::fs::read_dir(&dir).unwrap().count());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reload_config_test() {
+        let path = std::env::temp_dir().join(format!("jpdb_connect_{}.toml", std::process::id()));
+        let config = toml::from_str("auto_add = 3\napi_key = \"old-key-reload-test\"").unwrap();
+        let jpdb = JPDBConnection::new(reqwest::Client::new(), config).unwrap();
+        let jpdb: SharedProfiles = Arc::new(std::sync::RwLock::new(jpdb.into()));
+
+        let new = "auto_add = 5\nauto_forq = true\napi_key = \"new-key-reload-test\"";
+        std::fs::write(&path, new).unwrap();
+        reload_config(&path, &Args::default(), &jpdb).await.unwrap();
+        assert_eq!(Some(5), jpdb.read().unwrap().default.config.auto_add);
+        assert!(jpdb.read().unwrap().default.config.auto_forq);
+        // only the secrets of the current config are hidden
+        let logged = "old-key-reload-test new-key-reload-test";
+        assert_eq!("old-key-reload-test ***", secret::redact(logged));
+
+        // a broken file keeps the last working config
+        std::fs::write(&path, "auto_add = \"five\"").unwrap();
+        assert!(reload_config(&path, &Args::default(), &jpdb).await.is_err());
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(Some(5), jpdb.read().unwrap().default.config.auto_add);
+    }
+
+    #[test]
+    fn config_debug_test() {
+        let config: Config = toml::from_str(r#"session_id = "sid-debug-test""#).unwrap();
+        assert_eq!(
+            "sid-debug-test",
+            config.session_id.as_ref().unwrap().expose()
+        );
+        assert!(!format!("{:?}", config).contains("sid-debug-test"));
+    }
+
+    #[test]
+    fn api_key_test() {
+        let config: Config = toml::from_str(r#"api_key = "secret""#).unwrap();
+        let version = action(r#"{"action": "version", "version": 6}"#);
+        assert!(!is_authorized(&version, &config));
+        let wrong = action(r#"{"action": "version", "version": 6, "key": "guess"}"#);
+        assert!(!is_authorized(&wrong, &config));
+        let right = action(r#"{"action": "version", "version": 6, "key": "secret"}"#);
+        assert!(is_authorized(&right, &config));
+        let permission = action(r#"{"action": "requestPermission", "version": 6}"#);
+        assert!(is_authorized(&permission, &config));
+
+        let config: Config = toml::from_str("").unwrap();
+        assert!(is_authorized(&version, &config));
     }
 }
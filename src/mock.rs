@@ -0,0 +1,616 @@
+//! a fake jpdb for end-to-end tests, it serves copies of jpdb's pages from tests/fixtures,
+//! trimmed to the parts jpdb-connect reads, and remembers every form which is posted to it
+use crate::jpdb::JPDBConnection;
+use crate::profile::Profiles;
+use crate::{connect_profiles, parse_config, routes, Args, Cache, Config};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+use warp::path::FullPath;
+use warp::Filter;
+
+struct Word {
+    vid: &'static str,
+    sid: &'static str,
+    rid: &'static str,
+    spelling: &'static str,
+    reading: &'static str,
+    /// the search page which finds it
+    search: &'static str,
+    /// its vocabulary page, the tags show if it is known
+    page: &'static str,
+}
+
+/// every word the fake jpdb knows
+const VOCABULARY: [Word; 3] = [
+    Word {
+        vid: "1259620",
+        sid: "1406264136",
+        rid: "1437918808",
+        spelling: "見事",
+        reading: "みごと",
+        search: include_str!("../tests/fixtures/search_migoto.html"),
+        page: include_str!("../tests/fixtures/vocabulary_migoto.html"),
+    },
+    Word {
+        vid: "1008290",
+        sid: "2148104656",
+        rid: "0",
+        spelling: "てっきり",
+        reading: "てっきり",
+        search: include_str!("../tests/fixtures/search_tekkiri.html"),
+        page: include_str!("../tests/fixtures/vocabulary_tekkiri.html"),
+    },
+    Word {
+        vid: "1438940",
+        sid: "1406264137",
+        rid: "0",
+        spelling: "北",
+        reading: "きた",
+        search: include_str!("../tests/fixtures/search_kita.html"),
+        page: include_str!("../tests/fixtures/vocabulary_kita.html"),
+    },
+];
+
+/// jpdb's answer to a search without results
+const NO_RESULTS: &str = include_str!("../tests/fixtures/search_none.html");
+
+/// the deck list: Mining (3), Core & more (7), Mining (9) and jpdb (11)
+const LEARN: &str = include_str!("../tests/fixtures/learn.html");
+
+/// a deck page, the mock puts the vocabulary of the deck at `<!-- entries -->`
+const DECK: &str = include_str!("../tests/fixtures/deck.html");
+
+#[derive(Debug, Clone)]
+pub struct Post {
+    /// path and query, e.g. `/edit-shown-sentence?v=1&s=2&r=3`
+    pub url: String,
+    pub form: HashMap<String, String>,
+    /// the sid cookie, so tests can tell the accounts apart
+    pub sid: Option<String>,
+}
+
+#[derive(Default)]
+pub struct MockState {
+    pub posts: Vec<Post>,
+    /// deck id -> vids added to it
+    pub decks: HashMap<u64, Vec<String>>,
+    /// answers everything with 503 Service Unavailable
+    pub down: bool,
+    /// answers this many requests with 429 Too Many Requests
+    pub too_many: u32,
+    /// how often a page of a deck was loaded
+    pub deck_pages: u32,
+    pub searches: u32,
+    /// how often the deck list on /learn was loaded
+    pub deck_lists: u32,
+}
+
+pub struct MockJpdb {
+    pub base_url: String,
+    pub state: Arc<Mutex<MockState>>,
+}
+
+impl MockJpdb {
+    /// serves the fake jpdb on a free port until the test ends
+    pub fn start() -> MockJpdb {
+        let state = Arc::new(Mutex::new(MockState::default()));
+        let filter_state = state.clone();
+        let filter = warp::method()
+            .and(warp::path::full())
+            .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+            .and(warp::header::optional::<String>("cookie"))
+            .and(warp::body::bytes())
+            .map(
+                move |method, path: FullPath, query: String, cookie, body: Bytes| {
+                    let mut state = filter_state.lock().unwrap();
+                    let request = (method, path.as_str(), query.as_str(), cookie);
+                    let (status, page) = answer(&mut state, request, &body);
+                    let reply = warp::reply::with_status(warp::reply::html(page), status);
+                    // like a busy jpdb, retries still wait for the backoff
+                    warp::reply::with_header(reply, "retry-after", "0")
+                },
+            );
+        let (addr, server) = warp::serve(filter).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        MockJpdb {
+            base_url: format!("http://{addr}"),
+            state,
+        }
+    }
+
+    /// a connection to the fake jpdb, configured like jpdb_connect.toml
+    pub fn connect(&self, config: &str) -> JPDBConnection {
+        let config = format!("base_url = \"{}\"\n{config}", self.base_url);
+        let config: Config = toml::from_str(&config).unwrap();
+        JPDBConnection::new(reqwest::Client::new(), config).unwrap()
+    }
+
+    /// connections for the top level config and its profiles
+    pub async fn connect_profiles(&self, config: &str) -> Profiles {
+        let config = format!("base_url = \"{}\"\n{config}", self.base_url);
+        let configs = parse_config(&config, &Args::default()).unwrap();
+        connect_profiles(configs, None, None).await.unwrap()
+    }
+
+    /// forms posted to urls starting with `prefix`
+    pub fn posts(&self, prefix: &str) -> Vec<Post> {
+        let state = self.state.lock().unwrap();
+        let posts = state.posts.iter().filter(|p| p.url.starts_with(prefix));
+        posts.cloned().collect()
+    }
+}
+
+/// (method, path, query, cookie header)
+type MockRequest<'a> = (warp::http::Method, &'a str, &'a str, Option<String>);
+
+fn answer(state: &mut MockState, request: MockRequest, body: &[u8]) -> (StatusCode, String) {
+    let (method, path, query, cookie) = request;
+    if state.down {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            page("down for maintenance"),
+        );
+    }
+    if state.too_many > 0 {
+        state.too_many -= 1;
+        return (StatusCode::TOO_MANY_REQUESTS, page("slow down"));
+    }
+    let query: HashMap<String, String> = serde_urlencoded::from_str(query).unwrap_or_default();
+    if method == warp::http::Method::POST {
+        let form = serde_urlencoded::from_bytes(body).unwrap_or_default();
+        let url = match query.is_empty() {
+            true => path.to_string(),
+            false => format!("{path}?{}", serde_urlencoded::to_string(&query).unwrap()),
+        };
+        if let Some(deck_id) = path
+            .strip_prefix("/deck/")
+            .and_then(|rest| rest.strip_suffix("/add"))
+            .and_then(|id| id.parse().ok())
+        {
+            let form: &HashMap<String, String> = &form;
+            let vid = form.get("v").cloned().unwrap_or_default();
+            state.decks.entry(deck_id).or_default().push(vid);
+        }
+        let sid = cookie.and_then(|c| c.strip_prefix("sid=").map(String::from));
+        state.posts.push(Post { url, form, sid });
+        return (StatusCode::OK, String::new());
+    }
+
+    let segments: Vec<_> = path.trim_start_matches('/').split('/').collect();
+    match segments.as_slice() {
+        ["search"] => {
+            state.searches += 1;
+            let q = query.get("q").cloned().unwrap_or_default();
+            // like jpdb, kana finds words by their reading too
+            let found = VOCABULARY
+                .iter()
+                .find(|w| w.spelling == q || w.reading == q);
+            let page = found.map_or(NO_RESULTS, |w| w.search);
+            (StatusCode::OK, page.to_string())
+        }
+        // only the id is ascii, the rest of the path is percent encoded
+        ["vocabulary", vid, ..] => match VOCABULARY.iter().find(|w| w.vid == *vid) {
+            Some(w) => (StatusCode::OK, w.page.to_string()),
+            None => (StatusCode::NOT_FOUND, page("not found")),
+        },
+        ["learn"] => {
+            state.deck_lists += 1;
+            (StatusCode::OK, LEARN.to_string())
+        }
+        ["deck"] => {
+            state.deck_pages += 1;
+            let id = query.get("id").and_then(|id| id.parse::<u64>().ok());
+            let offset = query.get("offset").and_then(|o| o.parse().ok());
+            let vids = id.and_then(|id| state.decks.get(&id)).cloned();
+            // everything fits on the first page
+            let links = match offset {
+                Some(0) | None => vids.unwrap_or_default(),
+                Some(_) => Vec::new(),
+            };
+            let links = links
+                .iter()
+                .filter_map(|vid| VOCABULARY.iter().find(|w| w.vid == vid))
+                .map(vocabulary_entry);
+            let page = DECK.replace("<!-- entries -->", &links.collect::<String>());
+            (StatusCode::OK, page)
+        }
+        _ => (StatusCode::OK, page("")),
+    }
+}
+
+/// a row of the vocabulary list on a deck page
+fn vocabulary_entry(w: &Word) -> String {
+    format!(
+        r##"<div class="entry"><div class="vocabulary-spelling"><a class="plain" href="/vocabulary/{}/{}/{}#a">{}</a></div></div>
+"##,
+        w.vid, w.spelling, w.reading, w.spelling
+    )
+}
+
+fn page(content: &str) -> String {
+    format!("<!DOCTYPE html><html><body>{content}</body></html>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::browse_url;
+    use serde_json::{json, Value};
+    use tokio::sync::Mutex;
+
+    fn note_id(word: &Word) -> i64 {
+        crate::parsing::note_id(word.vid, word.spelling).unwrap()
+    }
+
+    /// sends the AnkiConnect request through the same filter the server uses
+    async fn request(jpdb: &JPDBConnection, body: Value) -> Value {
+        request_to(jpdb.clone().into(), "/", body).await
+    }
+
+    async fn request_to(profiles: Profiles, path: &str, body: Value) -> Value {
+        let cache = Arc::new(Mutex::new(Cache::default()));
+        let reply = warp::test::request()
+            .method("POST")
+            .path(path)
+            .body(body.to_string())
+            .reply(&routes(Arc::new(RwLock::new(profiles)), cache))
+            .await;
+        serde_json::from_slice(reply.body()).unwrap()
+    }
+
+    #[test]
+    fn fixtures_test() {
+        for w in &VOCABULARY {
+            let id = crate::parsing::find_vocab_id(w.page).unwrap();
+            assert_eq!((w.vid, w.sid, w.rid), (&*id.v, &*id.s, &*id.r));
+            let results = crate::parsing::find_search_results(w.search);
+            assert!(results.iter().any(|r| r.link.v == w.vid));
+        }
+        let decks = crate::parsing::find_decks(LEARN);
+        let names: Vec<_> = decks.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(vec!["Mining", "Core & more", "Mining", "jpdb"], names);
+        assert!(crate::parsing::find_search_results(NO_RESULTS).is_empty());
+    }
+
+    #[tokio::test]
+    async fn add_note_test() {
+        let mock = MockJpdb::start();
+        let jpdb = mock.connect(
+            r#"session_id = "test"
+auto_add = 3
+auto_forq = true
+auto_unlock = true
+add_mined_sentences = true
+add_custom_definition = true"#,
+        );
+        let note = json!({
+            "deckName": "Mining",
+            "fields": {
+                "word": "見事",
+                "reading": "みごと",
+                "sentence": "見事な演技",
+                "definition": "splendid",
+            }
+        });
+        let body = json!({"action": "addNote", "version": 6, "params": {"note": note}});
+        let answer = request(&jpdb, body).await;
+        assert_eq!(json!(null), answer["error"]);
+        assert_eq!(json!(note_id(&VOCABULARY[0])), answer["result"]);
+
+        let added = mock.posts("/deck/3/add");
+        assert_eq!(1, added.len());
+        assert_eq!("1259620", added[0].form["v"]);
+        assert_eq!("1437918808", added[0].form["r"]);
+        assert_eq!("/vocabulary/1259620/見事/みごと", added[0].form["origin"]);
+        assert_eq!("1406264136", mock.posts("/prioritize")[0].form["s"]);
+        assert_eq!(1, mock.posts("/force-unlock").len());
+        assert!(mock.posts("/mark-as-not-known").is_empty());
+        let sentence = &mock.posts("/edit-shown-sentence")[0];
+        assert_eq!("見事な演技", sentence.form["sentence"]);
+        assert!(sentence.url.contains("v=1259620"));
+        let definition = &mock.posts("/edit_shown_meanings")[0];
+        assert_eq!("splendid", definition.form["custom-definition"]);
+    }
+
+    #[tokio::test]
+    async fn logged_out_note_id_test() {
+        let mock = MockJpdb::start();
+        let jpdb = mock.connect("");
+        let add = |word: &str| {
+            let note = json!({"fields": {"word": word, "sentence": ""}});
+            json!({"action": "addNote", "version": 6, "params": {"note": note}})
+        };
+        // the same id as with a session_id
+        let answer = request(&jpdb, add("見事")).await;
+        assert_eq!(json!(note_id(&VOCABULARY[0])), answer["result"]);
+        let answer = request(&jpdb, add("ぬるぽ")).await;
+        assert_eq!(json!("can't find card"), answer["error"]);
+    }
+
+    #[tokio::test]
+    async fn add_notes_test() {
+        let mock = MockJpdb::start();
+        let jpdb = mock.connect("session_id = \"test\"\nauto_add = 3");
+        let notes = json!([
+            {"fields": {"word": "見事", "reading": "みごと", "sentence": ""}},
+            // jpdb doesn't know this one
+            {"fields": {"word": "ぬるぽ", "sentence": ""}},
+        ]);
+        let body = json!({"action": "addNotes", "version": 6, "params": {"notes": notes}});
+        let answer = request(&jpdb, body).await;
+        assert_eq!(json!(null), answer["error"]);
+        assert_eq!(json!([note_id(&VOCABULARY[0]), null]), answer["result"]);
+        let added = mock.posts("/deck/3/add");
+        assert_eq!(1, added.len());
+        assert_eq!("1259620", added[0].form["v"]);
+    }
+
+    #[tokio::test]
+    async fn sentence_test() {
+        let mock = MockJpdb::start();
+        let jpdb = mock.connect("session_id = \"test\"\nadd_mined_sentences = true");
+        let add = |sentence: &str| {
+            let note =
+                json!({"fields": {"word": "見事", "reading": "みごと", "sentence": sentence}});
+            json!({"action": "addNote", "version": 6, "params": {"note": note}})
+        };
+        // written in kana
+        request(&jpdb, add("みごとだね")).await;
+        let sentences = mock.posts("/edit-shown-sentence");
+        assert_eq!("みごとだね", sentences[0].form["sentence"]);
+        // a sentence without the word isn't set
+        let answer = request(&jpdb, add("パンを食べた")).await;
+        assert_eq!(json!(null), answer["error"]);
+        assert_eq!(1, mock.posts("/edit-shown-sentence").len());
+    }
+
+    #[tokio::test]
+    async fn homophone_test() {
+        let mock = MockJpdb::start();
+        let jpdb = mock.connect("session_id = \"test\"\nauto_add = 3");
+        // neither 来た nor 来る are known, the reading きた would find 北
+        let note = json!({"fields": {"word": "来た", "reading": "きた", "sentence": ""}});
+        let add = json!({"action": "addNote", "version": 6, "params": {"note": note}});
+        let answer = request(&jpdb, add).await;
+        assert_eq!(json!("can't find card"), answer["error"]);
+        assert!(mock.posts("/deck/3/add").is_empty());
+
+        // popups only search the word as it was sent
+        let searches = mock.state.lock().unwrap().searches;
+        let body = json!({"action": "canAddNotes", "version": 6, "params": {"notes": [note]}});
+        let answer = request(&jpdb, body).await;
+        assert_eq!(json!([true]), answer["result"]);
+        assert_eq!(searches + 1, mock.state.lock().unwrap().searches);
+    }
+
+    #[tokio::test]
+    async fn can_add_notes_test() {
+        let mock = MockJpdb::start();
+        let jpdb = mock.connect("session_id = \"test\"\nauto_add = 3");
+        let answer = request(&jpdb, json!({"action": "deckNames", "version": 6})).await;
+        let names = json!(["jpdb", "Mining", "Core & more", "Mining (9)", "jpdb (11)"]);
+        assert_eq!(names, answer["result"]);
+        let answer = request(&jpdb, json!({"action": "deckNamesAndIds", "version": 6})).await;
+        let ids =
+            json!({"jpdb": 3, "Mining": 3, "Core & more": 7, "Mining (9)": 9, "jpdb (11)": 11});
+        assert_eq!(ids, answer["result"]);
+
+        let notes = json!([
+            {"fields": {"word": "見事", "reading": "みごと", "sentence": ""}},
+            {"fields": {"word": "てっきり", "sentence": ""}},
+        ]);
+        let body = json!({"action": "canAddNotes", "version": 6, "params": {"notes": notes}});
+        let answer = request(&jpdb, body.clone()).await;
+        // てっきり is already known
+        assert_eq!(json!([true, false]), answer["result"]);
+        // the deck is loaded once, the next popup uses it again
+        let deck_pages = mock.state.lock().unwrap().deck_pages;
+        request(&jpdb, body.clone()).await;
+        assert_eq!(deck_pages, mock.state.lock().unwrap().deck_pages);
+
+        let note = json!({"fields": {"word": "見事", "reading": "みごと", "sentence": ""}});
+        let add = json!({"action": "addNote", "version": 6, "params": {"note": note}});
+        request(&jpdb, add).await;
+        let answer = request(&jpdb, body).await;
+        assert_eq!(json!([false, false]), answer["result"]);
+
+        // notes naming a deck use the deck list from before
+        let deck_lists = mock.state.lock().unwrap().deck_lists;
+        for word in ["見事", "てっきり"] {
+            let note = json!({"deckName": "Core & more", "fields": {"word": word, "sentence": ""}});
+            request(
+                &jpdb,
+                json!({"action": "addNote", "version": 6, "params": {"note": note}}),
+            )
+            .await;
+        }
+        assert_eq!(deck_lists, mock.state.lock().unwrap().deck_lists);
+        assert_eq!(2, mock.posts("/deck/7/add").len());
+        let note = json!({"deckName": "Mining (9)", "fields": {"word": "見事", "sentence": ""}});
+        request(
+            &jpdb,
+            json!({"action": "addNote", "version": 6, "params": {"note": note}}),
+        )
+        .await;
+        assert_eq!(1, mock.posts("/deck/9/add").len());
+    }
+
+    #[tokio::test]
+    async fn find_notes_test() {
+        let mock = MockJpdb::start();
+        let jpdb = mock.connect("session_id = \"test\"\nauto_add = 3");
+        let vids = VOCABULARY.iter().map(|w| w.vid.to_string()).collect();
+        mock.state.lock().unwrap().decks.insert(3, vids);
+        let migoto = note_id(&VOCABULARY[0]);
+        let tekkiri = note_id(&VOCABULARY[1]);
+        let find =
+            |query: &str| json!({"action": "findNotes", "version": 6, "params": {"query": query}});
+
+        let answer = request(&jpdb, find("word:見事")).await;
+        assert_eq!(json!([migoto]), answer["result"]);
+        let answer = request(&jpdb, find("deck:Mining reading:てっきり")).await;
+        assert_eq!(json!([tekkiri]), answer["result"]);
+        let answer = request(&jpdb, find(&format!("nid:{migoto}"))).await;
+        assert_eq!(json!([migoto]), answer["result"]);
+        // another spelling of the same vocab isn't in the deck
+        let answer = request(&jpdb, find(&format!("nid:{}", (1259620_i64 << 32) | 1))).await;
+        assert_eq!(json!([]), answer["result"]);
+        let answer = request(&jpdb, find("deck:Mining")).await;
+        assert!(answer["error"].as_str().unwrap().contains("need a word"));
+
+        let info =
+            |ids: Value| json!({"action": "notesInfo", "version": 6, "params": {"notes": ids}});
+        let answer = request(&jpdb, info(json!([migoto, 42]))).await;
+        assert_eq!(json!(migoto), answer["result"][0]["noteId"]);
+        assert_eq!(
+            json!("見事"),
+            answer["result"][0]["fields"]["word"]["value"]
+        );
+        assert_eq!(json!({}), answer["result"][1]);
+        let answer = request(&jpdb, info(json!([]))).await;
+        assert_eq!(json!([]), answer["result"]);
+    }
+
+    #[tokio::test]
+    async fn browse_url_test() {
+        let mock = MockJpdb::start();
+        let jpdb = mock.connect("session_id = \"test\"\nauto_add = 3");
+        let vids = vec![VOCABULARY[0].vid.to_string()];
+        mock.state.lock().unwrap().decks.insert(3, vids);
+        let cache = Arc::new(Mutex::new(Cache::default()));
+        let detail_url = format!("{}/vocabulary/1259620/見事/みごと", mock.base_url);
+        // guiBrowse would open the url in a browser
+        let browse = |query: String| {
+            let cache = cache.clone();
+            let mut jpdb = jpdb.clone();
+            async move { browse_url(Some(&query), &mut jpdb, &cache).await }
+        };
+
+        assert_eq!(detail_url, browse("word:見事".into()).await.unwrap());
+        let migoto = note_id(&VOCABULARY[0]);
+        assert_eq!(detail_url, browse(format!("nid:{migoto}")).await.unwrap());
+        assert!(browse("nid:42".into()).await.is_err());
+        // cards added while running are opened without asking jpdb
+        let opened = "https://jpdb.io/vocabulary/42/added".to_string();
+        cache.lock().await.opened.insert(42, opened.clone());
+        assert_eq!(opened, browse("nid:42".into()).await.unwrap());
+        // 北 is in no deck
+        let kita = format!("{}/vocabulary/1438940/北/きた", mock.base_url);
+        let id = note_id(&VOCABULARY[2]);
+        assert_eq!(kita, browse(format!("nid:{id}")).await.unwrap());
+        assert_eq!(kita, browse(format!("nid:7,{id},{migoto}")).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn queue_test() {
+        let mock = MockJpdb::start();
+        let jpdb = mock.connect("session_id = \"test\"\nauto_add = 3");
+        mock.state.lock().unwrap().down = true;
+        let note = json!({"fields": {"word": "見事", "reading": "みごと", "sentence": ""}});
+        let add = json!({"action": "addNote", "version": 6, "params": {"note": note}});
+        let answer = request(&jpdb, add).await;
+        assert_eq!(
+            json!("jpdb is unreachable, 見事 will be added later"),
+            answer["error"]
+        );
+        let queued = request(&jpdb, json!({"action": "queuedNotes", "version": 6})).await;
+        assert_eq!(json!("見事"), queued["result"][0]["note"]["fields"]["word"]);
+
+        mock.state.lock().unwrap().down = false;
+        let flush = request(&jpdb, json!({"action": "flushQueue", "version": 6})).await;
+        assert_eq!(json!(true), flush["result"]);
+        assert!(jpdb.queue.is_empty());
+        assert_eq!("1259620", mock.posts("/deck/3/add")[0].form["v"]);
+    }
+
+    #[tokio::test]
+    async fn queued_options_test() {
+        let mock = MockJpdb::start();
+        let mined = mock.connect("session_id = \"test\"\nauto_add = 3\nauto_forq = true");
+        mock.state.lock().unwrap().down = true;
+        let note = json!({"fields": {"word": "見事", "reading": "みごと", "sentence": ""}});
+        request(
+            &mined,
+            json!({"action": "addNote", "params": {"note": note}}),
+        )
+        .await;
+        mock.state.lock().unwrap().down = false;
+
+        // the config changed before jpdb was back
+        let mut replayed = mock.connect("session_id = \"test\"\nauto_add = 7");
+        replayed.queue = mined.queue.clone();
+        replayed.replay_queue().await.unwrap();
+        assert_eq!(1, mock.posts("/deck/3/add").len());
+        assert!(mock.posts("/deck/7/add").is_empty());
+        assert_eq!(1, mock.posts("/prioritize").len());
+    }
+
+    #[tokio::test]
+    async fn retry_test() {
+        let mock = MockJpdb::start();
+        let jpdb = mock.connect("session_id = \"test\"\nauto_add = 3");
+        mock.state.lock().unwrap().too_many = 2;
+        let note = json!({"fields": {"word": "見事", "reading": "みごと", "sentence": ""}});
+        let add = json!({"action": "addNote", "version": 6, "params": {"note": note}});
+        let answer = request(&jpdb, add).await;
+        assert_eq!(json!(null), answer["error"]);
+        assert_eq!(1, mock.posts("/deck/3/add").len());
+    }
+
+    #[tokio::test]
+    async fn profiles_test() {
+        let mock = MockJpdb::start();
+        let profiles = mock
+            .connect_profiles(
+                r#"session_id = "top"
+auto_add = 3
+
+[profiles.alice]
+session_id = "alice"
+auto_add = 7
+deck_names = ["Alice"]"#,
+            )
+            .await;
+        let note = |deck: &str| {
+            let note = json!({"deckName": deck, "fields": {"word": "見事", "reading": "みごと", "sentence": ""}});
+            json!({"action": "addNote", "version": 6, "params": {"note": note}})
+        };
+        request_to(profiles.clone(), "/alice", note("Mining")).await;
+        request_to(profiles.clone(), "/", note("Alice")).await;
+        request_to(profiles.clone(), "/", note("Mining")).await;
+
+        let added: Vec<_> = mock
+            .posts("/deck/")
+            .into_iter()
+            .map(|p| (p.url, p.sid))
+            .collect();
+        let alice = ("/deck/3/add".to_string(), Some("alice".to_string()));
+        // Mining is a deck of alice's account too, Alice only picks the profile
+        assert_eq!(alice, added[0]);
+        assert_eq!(("/deck/7/add".into(), Some("alice".into())), added[1]);
+        assert_eq!(("/deck/3/add".into(), Some("top".into())), added[2]);
+
+        let decks = request_to(
+            profiles.clone(),
+            "/",
+            json!({"action": "deckNames", "version": 6}),
+        )
+        .await;
+        assert_eq!(
+            json!([
+                "jpdb",
+                "Mining",
+                "Core & more",
+                "Mining (9)",
+                "jpdb (11)",
+                "Alice"
+            ]),
+            decks["result"]
+        );
+        let unknown =
+            request_to(profiles, "/bob", json!({"action": "version", "version": 6})).await;
+        assert_eq!(json!("there is no profile named bob"), unknown["error"]);
+    }
+}
@@ -0,0 +1,276 @@
+//! other ways to write a word, for when jpdb doesn't know the one yomichan sent
+use std::ops::Range;
+
+/// (inflected ending, dictionary ending), only the longest endings which fit are used
+const DEINFLECTIONS: &[(&str, &str)] = &[
+    // する and くる
+    ("しました", "する"),
+    ("しません", "する"),
+    ("します", "する"),
+    ("しない", "する"),
+    ("した", "する"),
+    ("して", "する"),
+    ("きました", "くる"),
+    ("きます", "くる"),
+    ("こない", "くる"),
+    ("きた", "くる"),
+    ("きて", "くる"),
+    // i-adjectives
+    ("くなかった", "い"),
+    ("かった", "い"),
+    ("くない", "い"),
+    ("ければ", "い"),
+    ("くて", "い"),
+    // godan
+    ("きました", "く"),
+    ("ぎました", "ぐ"),
+    ("しました", "す"),
+    ("ちました", "つ"),
+    ("にました", "ぬ"),
+    ("びました", "ぶ"),
+    ("みました", "む"),
+    ("りました", "る"),
+    ("いました", "う"),
+    ("きます", "く"),
+    ("ぎます", "ぐ"),
+    ("します", "す"),
+    ("ちます", "つ"),
+    ("にます", "ぬ"),
+    ("びます", "ぶ"),
+    ("みます", "む"),
+    ("ります", "る"),
+    ("います", "う"),
+    ("かない", "く"),
+    ("がない", "ぐ"),
+    ("さない", "す"),
+    ("たない", "つ"),
+    ("なない", "ぬ"),
+    ("ばない", "ぶ"),
+    ("まない", "む"),
+    ("らない", "る"),
+    ("わない", "う"),
+    ("った", "う"),
+    ("った", "つ"),
+    ("った", "る"),
+    ("って", "う"),
+    ("って", "つ"),
+    ("って", "る"),
+    ("んだ", "む"),
+    ("んだ", "ぶ"),
+    ("んだ", "ぬ"),
+    ("んで", "む"),
+    ("んで", "ぶ"),
+    ("んで", "ぬ"),
+    ("いた", "く"),
+    ("いて", "く"),
+    ("いだ", "ぐ"),
+    ("いで", "ぐ"),
+    ("した", "す"),
+    ("して", "す"),
+    // ichidan
+    ("ました", "る"),
+    ("ません", "る"),
+    ("ます", "る"),
+    ("ない", "る"),
+    ("た", "る"),
+    ("て", "る"),
+];
+
+fn is_hiragana(c: char) -> bool {
+    ('\u{3041}'..='\u{3096}').contains(&c)
+}
+
+fn is_katakana(c: char) -> bool {
+    ('\u{30a1}'..='\u{30f6}').contains(&c)
+}
+
+fn is_kanji(c: char) -> bool {
+    ('\u{4e00}'..='\u{9fff}').contains(&c) || c == '々'
+}
+
+/// true if the word is written in hiragana or katakana only
+pub fn is_kana(word: &str) -> bool {
+    !word.is_empty()
+        && word
+            .chars()
+            .all(|c| is_hiragana(c) || is_katakana(c) || c == 'ー')
+}
+
+/// the katakana and hiragana blocks are the same distance apart everywhere
+fn shift_kana(word: &str, from: fn(char) -> bool, offset: i32) -> String {
+    word.chars()
+        .map(|c| match from(c) {
+            true => char::from_u32((c as i32 + offset) as u32).unwrap_or(c),
+            false => c,
+        })
+        .collect()
+}
+
+pub fn to_hiragana(word: &str) -> String {
+    shift_kana(word, is_katakana, -0x60)
+}
+
+pub fn to_katakana(word: &str) -> String {
+    shift_kana(word, is_hiragana, 0x60)
+}
+
+/// 取り扱い -> 取扱い, okurigana between two kanji is often left out
+fn without_inner_okurigana(word: &str) -> String {
+    let chars: Vec<char> = word.chars().collect();
+    let mut result = String::new();
+    for (i, c) in chars.iter().enumerate() {
+        let kanji_before = chars[..i].last().is_some_and(|c| is_kanji(*c));
+        let kanji_after = chars[i + 1..]
+            .iter()
+            .find(|c| !is_hiragana(**c))
+            .is_some_and(|c| is_kanji(*c));
+        if !(is_hiragana(*c) && kanji_before && kanji_after) {
+            result.push(*c);
+        }
+    }
+    result
+}
+
+/// the rules from [DEINFLECTIONS] which could have conjugated the word
+fn deinflections(word: &str) -> Vec<(&'static str, &'static str)> {
+    // a stem has to remain, otherwise た would turn into る
+    let fitting: Vec<_> = DEINFLECTIONS
+        .iter()
+        .filter(|(ending, _)| word.len() > ending.len() && word.ends_with(ending))
+        .copied()
+        .collect();
+    let longest = fitting.iter().map(|(ending, _)| ending.len()).max();
+    fitting
+        .into_iter()
+        .filter(|(ending, _)| Some(ending.len()) == longest)
+        .collect()
+}
+
+fn deinflect(word: &str, (ending, replacement): (&str, &str)) -> Option<String> {
+    let stem = word.strip_suffix(ending)?;
+    Some(format!("{stem}{replacement}"))
+}
+
+fn push_new<T: PartialEq>(list: &mut Vec<T>, item: T) {
+    if !list.contains(&item) {
+        list.push(item);
+    }
+}
+
+/// (word, reading) pairs to search for, the original first and the reading last
+/// a reading only stays if it can be changed the same way as the word
+pub fn search_variants(word: &str, reading: Option<&str>) -> Vec<(String, Option<String>)> {
+    let mut variants = vec![(word.to_string(), reading.map(String::from))];
+    if is_kana(word) {
+        push_new(&mut variants, (to_hiragana(word), reading.map(to_hiragana)));
+        push_new(&mut variants, (to_katakana(word), reading.map(to_katakana)));
+    }
+    push_new(
+        &mut variants,
+        (without_inner_okurigana(word), reading.map(String::from)),
+    );
+    for rule in deinflections(word) {
+        let form = deinflect(word, rule).unwrap_or_default();
+        push_new(
+            &mut variants,
+            (form, reading.and_then(|r| deinflect(r, rule))),
+        );
+    }
+    // the kana form finds words jpdb spells with kanji, e.g. これ for 此れ
+    // it matches every homophone too, so it goes after the dictionary forms
+    if let Some(reading) = reading.filter(|r| *r != word) {
+        push_new(
+            &mut variants,
+            (reading.to_string(), Some(reading.to_string())),
+        );
+    }
+    variants
+}
+
+/// where and how the word is written in the sentence, as a byte range
+/// conjugated words are found by their stem
+pub fn find_in_sentence(sentence: &str, word: &str) -> Option<Range<usize>> {
+    let mut forms = vec![word.to_string()];
+    if is_kana(word) {
+        forms.push(to_hiragana(word));
+        forms.push(to_katakana(word));
+    }
+    forms.push(without_inner_okurigana(word));
+    for form in forms {
+        if let Some(start) = sentence.find(&form) {
+            return Some(start..start + form.len());
+        }
+    }
+    // 食べる in 食べた, the longest conjugation deinflections could undo
+    let mut found: Option<Range<usize>> = None;
+    for (ending, replacement) in DEINFLECTIONS {
+        let Some(stem) = word.strip_suffix(replacement).filter(|s| !s.is_empty()) else {
+            continue;
+        };
+        let conjugated = format!("{stem}{ending}");
+        if let Some(start) = sentence.find(&conjugated) {
+            let candidate = start..start + conjugated.len();
+            if found.as_ref().is_none_or(|f| f.len() < candidate.len()) {
+                found = Some(candidate);
+            }
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kana_test() {
+        assert_eq!("てっきり", to_hiragana("テッキリ"));
+        assert_eq!("テッキリ", to_katakana("てっきり"));
+        assert_eq!("コーヒー", to_katakana("コーヒー"));
+        assert!(is_kana("コーヒー"));
+        assert!(!is_kana("見事"));
+    }
+
+    fn dictionary_forms(word: &str) -> Vec<String> {
+        let rules = deinflections(word).into_iter();
+        rules.filter_map(|rule| deinflect(word, rule)).collect()
+    }
+
+    #[test]
+    fn dictionary_forms_test() {
+        assert_eq!(vec!["食べる"], dictionary_forms("食べました"));
+        assert_eq!(vec!["勉強する", "勉強す"], dictionary_forms("勉強した"));
+        assert_eq!(vec!["行う", "行つ", "行る"], dictionary_forms("行った"));
+        assert_eq!(vec!["高い"], dictionary_forms("高かった"));
+        assert!(dictionary_forms("見事").is_empty());
+    }
+
+    #[test]
+    fn search_variants_test() {
+        let variants = search_variants("取り扱った", Some("とりあつかった"));
+        assert_eq!(
+            ("取り扱った".to_string(), Some("とりあつかった".to_string())),
+            variants[0]
+        );
+        assert!(variants.contains(&("取扱った".into(), Some("とりあつかった".into()))));
+        assert!(variants.contains(&("とりあつかった".into(), Some("とりあつかった".into()))));
+        assert!(variants.contains(&("取り扱う".into(), Some("とりあつかう".into()))));
+
+        // 来た is 来る, not 北
+        let variants = search_variants("来た", Some("きた"));
+        let position = |word: &str| variants.iter().position(|(w, _)| w == word).unwrap();
+        assert!(position("来る") < position("きた"));
+    }
+
+    fn found<'a>(sentence: &'a str, word: &str) -> Option<&'a str> {
+        find_in_sentence(sentence, word).map(|range| &sentence[range])
+    }
+
+    #[test]
+    fn find_in_sentence_test() {
+        assert_eq!(Some("見事"), found("見事な演技", "見事"));
+        assert_eq!(Some("食べた"), found("パンを食べたよ", "食べる"));
+        assert_eq!(Some("テッキリ"), found("テッキリ", "てっきり"));
+        assert_eq!(None, found("パンを食べたよ", "見事"));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::normalize::is_kana;
 use anyhow::{anyhow, Result};
 use chumsky::prelude::*;
 use chumsky::text::digits;
@@ -10,24 +11,203 @@ fn parse_login_prompt() -> impl Parser<char, bool, Error = Simple<char>> {
     take_until(just("https://jpdb.io/login_with_google")).map(|_| true)
 }
 
-pub fn find_detail_url(body: &str, vocab: &str, reading: &str) -> Result<String> {
-    parse_detail_url(vocab, reading)
-        .parse(body)
-        .map_err(|e| anyhow!("{e:?}"))
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct VocabularyLink {
+    pub v: String,
+    pub spelling: String,
+    pub reading: Option<String>,
+}
+
+impl VocabularyLink {
+    pub fn rel_url(&self) -> String {
+        match self.reading {
+            Some(ref reading) => format!("/vocabulary/{}/{}/{}", self.v, self.spelling, reading),
+            None => format!("/vocabulary/{}/{}", self.v, self.spelling),
+        }
+    }
+
+    /// kana only words have no reading in their url
+    pub fn reading_or_spelling(&self) -> &str {
+        self.reading.as_deref().unwrap_or(&self.spelling)
+    }
+
+    pub fn note_id(&self) -> Option<i64> {
+        note_id(&self.v, &self.spelling)
+    }
 }
 
-fn parse_detail_url(vocab: &str, reading: &str) -> impl Parser<char, String, Error = Simple<char>> {
+/// anki note id for a card, the same word always gets the same id
+/// vocab id in the upper 32 bits, a hash of the spelling in the lower 32 bits,
+/// both are in the url, so the id is known without loading the card
+pub fn note_id(v: &str, spelling: &str) -> Option<i64> {
+    let v: u32 = v.parse().ok()?;
+    // FNV-1a, it has to stay the same across versions
+    let hash = spelling.bytes().fold(0x811c_9dc5_u32, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    });
+    Some(((v as i64) << 32) | hash as i64)
+}
+
+/// all vocabulary linked on a page, e.g. the vocabulary list of a deck
+pub fn find_vocabulary_links(body: &str) -> Vec<VocabularyLink> {
+    let links = parse_vocabulary_links().parse(body).unwrap_or_default();
+    links.into_iter().map(|(link, _)| link).collect()
+}
+
+/// a vocabulary entry on the search page
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SearchResult {
+    pub link: VocabularyLink,
+    /// position in jpdb's frequency list, lower is more common
+    pub rank: Option<u32>,
+}
+
+/// every vocabulary on the search page once, in the order jpdb lists them
+/// the rank is the first "Top N" between a link and the next one
+pub fn find_search_results(body: &str) -> Vec<SearchResult> {
+    let links = parse_vocabulary_links().parse(body).unwrap_or_default();
+    let chars: Vec<char> = body.chars().collect();
+    let mut results: Vec<SearchResult> = Vec::new();
+    for (i, (link, span)) in links.iter().enumerate() {
+        let next = links.get(i + 1).map_or(chars.len(), |(_, s)| s.start);
+        let between: String = chars[span.end..next].iter().collect();
+        let rank = parse_rank().parse(between).ok();
+        match results.iter_mut().find(|r| r.link == *link) {
+            // the same link often appears more than once per entry
+            Some(result) => result.rank = result.rank.or(rank),
+            None => results.push(SearchResult {
+                link: link.clone(),
+                rank,
+            }),
+        }
+    }
+    results
+}
+
+fn parse_rank() -> impl Parser<char, u32, Error = Simple<char>> {
+    take_until(just("Top ").ignore_then(digits::<char, Simple<char>>(10)))
+        .try_map(|(_a, b), span| b.parse().map_err(|_| Simple::custom(span, "rank too big")))
+}
+
+/// the search result which fits the word best and the ones which fit worse
+/// exact spellings win, then exact readings, then more common words
+pub fn choose_search_result(
+    results: Vec<SearchResult>,
+    word: &str,
+    reading: Option<&str>,
+) -> Option<(SearchResult, Vec<SearchResult>)> {
+    // a kana word can be the reading of a word jpdb spells with kanji
+    let kana = is_kana(word);
+    let mut candidates: Vec<_> = results
+        .into_iter()
+        .filter(|r| r.link.spelling == word || (kana && r.link.reading_or_spelling() == word))
+        .collect();
+    // stable, so equally good results keep jpdb's order
+    candidates.sort_by_key(|r| {
+        let reading_matches =
+            reading.is_some_and(|reading| r.link.reading_or_spelling() == reading);
+        (
+            r.link.spelling != word,
+            !reading_matches,
+            r.rank.unwrap_or(u32::MAX),
+        )
+    });
+    if candidates.is_empty() {
+        return None;
+    }
+    let chosen = candidates.remove(0);
+    Some((chosen, candidates))
+}
+
+type Span = std::ops::Range<usize>;
+
+fn parse_vocabulary_links() -> impl Parser<char, Vec<(VocabularyLink, Span)>, Error = Simple<char>>
+{
+    let segment = || {
+        filter(|c: &char| !matches!(c, '/' | '"' | '?' | '#'))
+            .repeated()
+            .at_least(1)
+            .collect::<String>()
+    };
     take_until(
-        just('"')
-            .ignore_then(just("/vocabulary/"))
-            .then(digits(10))
-            .then(just(format!("/{vocab}/{reading}")).or(just(format!("/{vocab}"))))
-            .map(|((a, b), c)| format!("{a}{b}{c}")),
+        just("\"/vocabulary/")
+            .ignore_then(digits(10))
+            .then_ignore(just('/'))
+            .then(segment())
+            .then(just('/').ignore_then(segment()).or_not())
+            .map_with_span(|((v, spelling), reading), span| {
+                let link = VocabularyLink {
+                    v,
+                    spelling,
+                    reading,
+                };
+                (link, span)
+            }),
+    )
+    .map(|(_a, b)| b)
+    .repeated()
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Deck {
+    pub id: u64,
+    pub name: String,
+}
+
+/// decks linked on a page like /learn, every deck only once
+pub fn find_decks(body: &str) -> Vec<Deck> {
+    let mut decks: Vec<Deck> = Vec::new();
+    for (id, name) in parse_deck_links().parse(body).unwrap_or_default() {
+        let (Ok(id), name) = (id.parse(), unescape_html(name.trim())) else {
+            continue;
+        };
+        if !name.is_empty() && !decks.iter().any(|d| d.id == id) {
+            decks.push(Deck { id, name });
+        }
+    }
+    decks
+}
+
+fn parse_deck_links() -> impl Parser<char, Vec<(String, String)>, Error = Simple<char>> {
+    take_until(
+        just(r#"href="/deck?id="#)
+            .ignore_then(digits(10))
+            .then_ignore(just('"'))
+            .then_ignore(filter(|c: &char| *c != '>').repeated())
+            .then_ignore(just('>'))
+            .then(filter(|c: &char| *c != '<').repeated().collect::<String>()),
+    )
+    .map(|(_a, b)| b)
+    .repeated()
+}
+
+fn unescape_html(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+/// texts of the tags shown on a vocab detail page, e.g. "Known" or "Locked"
+pub fn find_card_tags(body: &str) -> Vec<String> {
+    parse_card_tags().parse(body).unwrap_or_default()
+}
+
+fn parse_card_tags() -> impl Parser<char, Vec<String>, Error = Simple<char>> {
+    take_until(
+        just(r#"class="tag"#)
+            .ignore_then(just('"').or(just(' ')))
+            .ignore_then(filter(|c: &char| *c != '>').repeated())
+            .ignore_then(just('>'))
+            .ignore_then(filter(|c: &char| *c != '<').repeated().collect::<String>())
+            .map(|s| s.trim().to_string()),
     )
     .map(|(_a, b)| b)
+    .repeated()
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
 pub struct VocabId {
     pub v: String,
     pub s: String,
@@ -57,20 +237,102 @@ fn parse_vocab_id() -> impl Parser<char, VocabId, Error = Simple<char>> {
 mod tests {
     use super::*;
 
+    fn search_url_of(body: &str, word: &str, reading: Option<&str>) -> Option<String> {
+        let results = find_search_results(body);
+        choose_search_result(results, word, reading).map(|(chosen, _)| chosen.link.rel_url())
+    }
+
     #[test]
-    fn parse_detail_url_test() {
+    fn search_result_test() {
         let example = r#"href or whatever idc "/vocabulary/1259620/見事/みごと?lang=english#a""#;
-        let parsed = parse_detail_url("見事", "みごと").parse(example).unwrap();
-        assert_eq!("/vocabulary/1259620/見事/みごと", parsed)
+        let url = search_url_of(example, "見事", Some("みごと"));
+        assert_eq!(Some("/vocabulary/1259620/見事/みごと".into()), url);
     }
 
     #[test]
-    fn parse_detail_url_kana_only_test() {
+    fn search_result_kana_only_test() {
         let example = r#"href="/vocabulary/1008290/てっきり?lang=english""#;
-        let parsed = parse_detail_url("てっきり", "てっきり")
-            .parse(example)
-            .unwrap();
-        assert_eq!("/vocabulary/1008290/てっきり", parsed)
+        let url = search_url_of(example, "てっきり", Some("てっきり"));
+        assert_eq!(Some("/vocabulary/1008290/てっきり".into()), url);
+        assert_eq!(None, search_url_of(example, "見事", None));
+    }
+
+    #[test]
+    fn search_result_homograph_test() {
+        let example = r#"<div class="result vocabulary">
+            <a href="/vocabulary/1/上手/じょうず#a">上手</a><div class="tag">Top 3000</div>
+            <a href="/vocabulary/1/上手/じょうず#a">more</a></div>
+            <div class="result vocabulary"><a href="/vocabulary/2/上手/うわて#a">上手</a>
+            <div class="tag">Top 20000</div></div>
+            <div class="result vocabulary"><a href="/vocabulary/3/上手/かみて#a">上手</a>
+            <div class="tag">Top 9000</div></div>"#;
+        let results = find_search_results(example);
+        assert_eq!(3, results.len());
+        assert_eq!(Some(3000), results[0].rank);
+        assert_eq!(
+            Some("/vocabulary/2/上手/うわて".into()),
+            search_url_of(example, "上手", Some("うわて"))
+        );
+        // without a matching reading the most common one wins
+        let (chosen, rejected) = choose_search_result(results, "上手", Some("x")).unwrap();
+        assert_eq!("1", chosen.link.v);
+        assert_eq!(
+            vec!["3", "2"],
+            rejected.iter().map(|r| &r.link.v).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn parse_vocabulary_links_test() {
+        let example = r#"<a href="/vocabulary/1259620/見事/みごと#a">見事</a>
+            <a href="/vocabulary/1008290/てっきり?lang=english">てっきり</a>"#;
+        let parsed = find_vocabulary_links(example);
+        assert_eq!(
+            vec![
+                VocabularyLink {
+                    v: "1259620".into(),
+                    spelling: "見事".into(),
+                    reading: Some("みごと".into())
+                },
+                VocabularyLink {
+                    v: "1008290".into(),
+                    spelling: "てっきり".into(),
+                    reading: None
+                },
+            ],
+            parsed
+        );
+        assert_eq!("/vocabulary/1259620/見事/みごと", parsed[0].rel_url());
+    }
+
+    #[test]
+    fn find_decks_test() {
+        let example = r#"<div class="deck-title"><a href="/deck?id=3">Core &amp; more</a></div>
+            <a href="/deck?id=3">Core &amp; more</a>
+            <a href="/deck?id=3&amp;offset=50">next page</a>
+            <a href="/deck?id=12" class="link">Mined</a>"#;
+        let decks = find_decks(example);
+        assert_eq!(
+            vec![
+                Deck {
+                    id: 3,
+                    name: "Core & more".into()
+                },
+                Deck {
+                    id: 12,
+                    name: "Mined".into()
+                }
+            ],
+            decks
+        );
+    }
+
+    #[test]
+    fn parse_card_tags_test() {
+        let example = r#"<div class="tags"><div class="tag" style="">Known</div>
+            <div class="tag tooltip">Top 2000</div></div>"#;
+        let parsed = parse_card_tags().parse(example).unwrap();
+        assert_eq!(vec!["Known", "Top 2000"], parsed);
     }
 
     #[test]
@@ -79,4 +341,14 @@ mod tests {
         let parsed = parse_vocab_id().parse(example).unwrap();
         assert_eq!("1437918808", parsed.r);
     }
+
+    #[test]
+    fn note_id_test() {
+        let note_id = note_id("1414580", "見事").unwrap();
+        assert_eq!(1414580, note_id >> 32);
+        // changing this breaks the ids clients remember
+        assert_eq!(0xb79e_ed73, note_id & 0xFFFF_FFFF);
+        assert_ne!(Some(note_id), super::note_id("1414580", "美事"));
+        assert_eq!(None, super::note_id("x", "見事"));
+    }
 }
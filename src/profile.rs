@@ -0,0 +1,246 @@
+//! several jpdb accounts in one server, every [profiles.<name>] table is a config of its own
+use crate::anki_connect::AnkiConnectAction;
+use crate::jpdb::JPDBConnection;
+use crate::secret::{Env, Secret};
+use crate::Config;
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use toml::value::Table;
+use toml::Value;
+
+/// options for the whole server, a profile can't have its own
+const SERVER_KEYS: &[&str] = &[
+    "port",
+    "ip",
+    "log_level",
+    "rate_limit_requests",
+    "rate_limit_seconds",
+    "concurrency",
+    "buffer_size",
+    "lookup_cache_days",
+];
+
+/// a profile never uses the jpdb account of the top level config by accident
+/// the api_key is inherited after the secrets are read, it can come from an env var
+const NOT_INHERITED: &[&str] = &[
+    "session_id",
+    "session_id_file",
+    "jpdb_api_key",
+    "jpdb_api_key_file",
+    "api_key",
+    "api_key_file",
+    "deck_names",
+    // a deck of the top level account
+    "auto_add",
+];
+
+/// the top level config and the profiles, which inherit from it
+#[derive(Debug)]
+pub struct ProfileConfigs {
+    pub default: Config,
+    pub named: BTreeMap<String, Config>,
+}
+
+impl ProfileConfigs {
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut table: Table = toml::from_str(content)?;
+        let profiles = match table.remove("profiles") {
+            Some(Value::Table(profiles)) => profiles,
+            Some(_) => bail!("profiles have to be tables like [profiles.name]"),
+            None => Table::new(),
+        };
+        let default = Value::Table(table.clone()).try_into()?;
+        let inherited: Table = table
+            .into_iter()
+            .filter(|(key, _)| !NOT_INHERITED.contains(&key.as_str()))
+            .collect();
+        let mut named = BTreeMap::new();
+        for (name, profile) in profiles {
+            // the name is part of the url
+            let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+            if name.is_empty() || !name.chars().all(valid) {
+                bail!("profile names can only use a-z, 0-9, - and _, '{name}' doesn't work");
+            }
+            let Value::Table(profile) = profile else {
+                bail!("profile {name} has to be a table like [profiles.{name}]");
+            };
+            if let Some(key) = profile.keys().find(|k| SERVER_KEYS.contains(&k.as_str())) {
+                bail!("{key} is for the whole server, profile {name} can't have its own");
+            }
+            let mut merged = inherited.clone();
+            merged.extend(profile);
+            let config = Value::Table(merged)
+                .try_into()
+                .with_context(|| format!("profile {name} is invalid"))?;
+            named.insert(name, config);
+        }
+        Ok(ProfileConfigs { default, named })
+    }
+
+    /// secrets from env vars and files, with the prefix `JPDB_CONNECT`
+    /// `JPDB_CONNECT_ALICE_SESSION_ID` is for profile alice
+    pub fn read_secrets(&mut self, prefix: &str, env: Env) -> Result<()> {
+        self.default.read_secrets(prefix, env)?;
+        for (name, config) in self.named.iter_mut() {
+            let prefix = format!("{prefix}_{}", name.to_uppercase().replace('-', "_"));
+            config
+                .read_secrets(&prefix, env)
+                .with_context(|| format!("profile {name}"))?;
+            // otherwise everyone could use the profile without a key
+            if config.api_key.is_none() {
+                config.api_key = self.default.api_key.clone();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// the connection requests use, replaced as a whole when the config file changes
+pub type SharedProfiles = Arc<RwLock<Profiles>>;
+
+/// one connection per profile, they share the rate limit
+#[derive(Clone)]
+pub struct Profiles {
+    pub default: JPDBConnection,
+    pub named: BTreeMap<String, JPDBConnection>,
+}
+
+impl From<JPDBConnection> for Profiles {
+    fn from(default: JPDBConnection) -> Self {
+        Profiles {
+            default,
+            named: BTreeMap::new(),
+        }
+    }
+}
+
+impl Profiles {
+    pub fn get(&self, name: Option<&str>) -> Option<&JPDBConnection> {
+        match name {
+            Some(name) => self.named.get(name),
+            None => Some(&self.default),
+        }
+    }
+
+    /// (name, connection) of every profile, None is the top level one
+    pub fn all(&self) -> impl Iterator<Item = (Option<&str>, &JPDBConnection)> {
+        let named = self.named.iter().map(|(name, p)| (Some(name.as_str()), p));
+        std::iter::once((None, &self.default)).chain(named)
+    }
+
+    /// the profile from the url path, the api key or the deck, in that order
+    /// the top level config is used if none of them picks a profile
+    pub fn select(&self, path: &str, action: &AnkiConnectAction) -> Result<&JPDBConnection> {
+        let name = path.trim_matches('/');
+        if !name.is_empty() {
+            return self
+                .named
+                .get(name)
+                .with_context(|| format!("there is no profile named {name}"));
+        }
+        fn api_key(p: &JPDBConnection) -> Option<&str> {
+            p.config.api_key.as_ref().map(Secret::expose)
+        }
+        // the inherited key would always pick the first profile
+        let key = action
+            .key
+            .as_deref()
+            .filter(|k| Some(*k) != api_key(&self.default));
+        let by_key = key.and_then(|key| self.named.values().find(|p| api_key(p) == Some(key)));
+        let by_deck = || {
+            let deck = action.deck_name()?;
+            let mut profiles = self.named.values();
+            profiles.find(|p| p.config.deck_names.iter().any(|d| d == deck))
+        };
+        Ok(by_key.or_else(by_deck).unwrap_or(&self.default))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+session_id = "top-sid"
+auto_add = 3
+auto_forq = true
+api_key = "shared"
+
+[profiles.alice]
+session_id = "alice-sid"
+auto_add = 7
+deck_names = ["Alice"]
+
+[profiles.bob]
+auto_add = 5
+auto_forq = false
+api_key = "bob-key"
+"#;
+
+    #[test]
+    fn parse_test() {
+        let configs = ProfileConfigs::parse(CONFIG).unwrap();
+        assert_eq!(Some(3), configs.default.auto_add);
+        let alice = &configs.named["alice"];
+        assert_eq!("alice-sid", alice.session_id.as_ref().unwrap().expose());
+        assert_eq!(Some(7), alice.auto_add);
+        assert!(alice.auto_forq);
+        let bob = &configs.named["bob"];
+        assert!(bob.session_id.is_none());
+        assert!(!bob.auto_forq);
+        let carol = ProfileConfigs::parse("auto_add = 3\n[profiles.carol]\nauto_forq = true");
+        assert_eq!(None, carol.unwrap().named["carol"].auto_add);
+
+        assert!(ProfileConfigs::parse("[profiles.alice]\nport = 1").is_err());
+        assert!(ProfileConfigs::parse("[profiles.\"a/b\"]\nauto_add = 1").is_err());
+    }
+
+    #[test]
+    fn inherited_api_key_test() {
+        let mut configs = ProfileConfigs::parse(CONFIG).unwrap();
+        configs.read_secrets("JPDB_CONNECT", &|_| None).unwrap();
+        let key = |config: &Config| config.api_key.as_ref().map(|k| k.expose().to_string());
+        assert_eq!(Some("shared".into()), key(&configs.named["alice"]));
+        assert_eq!(Some("bob-key".into()), key(&configs.named["bob"]));
+
+        // the top level key from an env var protects the profiles too
+        let env = |name: &str| (name == "JPDB_CONNECT_API_KEY").then(|| "from-env".into());
+        let mut configs = ProfileConfigs::parse("[profiles.alice]\nauto_add = 7").unwrap();
+        configs.read_secrets("JPDB_CONNECT", &env).unwrap();
+        assert_eq!(Some("from-env".into()), key(&configs.named["alice"]));
+    }
+
+    fn action(json: &str) -> AnkiConnectAction {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[tokio::test]
+    async fn select_test() {
+        let configs = ProfileConfigs::parse(CONFIG).unwrap();
+        let connect = |config| JPDBConnection::new(reqwest::Client::new(), config).unwrap();
+        let profiles = Profiles {
+            default: connect(configs.default),
+            named: configs
+                .named
+                .into_iter()
+                .map(|(name, config)| (name, connect(config)))
+                .collect(),
+        };
+        let auto_add = |path: &str, json: &str| {
+            let selected = profiles.select(path, &action(json));
+            selected.map(|p| p.config.auto_add.unwrap())
+        };
+        let version = r#"{"action": "version", "version": 6, "key": "shared"}"#;
+        assert_eq!(3, auto_add("/", version).unwrap());
+        assert_eq!(7, auto_add("/alice", version).unwrap());
+        assert!(auto_add("/carol", version).is_err());
+
+        let bob = r#"{"action": "version", "version": 6, "key": "bob-key"}"#;
+        assert_eq!(5, auto_add("/", bob).unwrap());
+        let note = r#"{"action": "multi", "params": {"actions": [
+            {"action": "addNote", "params": {"note": {"deckName": "Alice", "fields": {"word": "見事", "sentence": ""}}}}
+        ]}}"#;
+        assert_eq!(7, auto_add("", note).unwrap());
+    }
+}
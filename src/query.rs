@@ -0,0 +1,97 @@
+use anyhow::{anyhow, Result};
+use chumsky::prelude::*;
+
+/// the small part of the anki search syntax jpdb-connect understands
+#[derive(Debug, PartialEq, Eq)]
+pub enum Term {
+    Deck(String),
+    Word(String),
+    Reading(String),
+    /// `nid:1,2,3` matches any of the ids
+    NoteIds(Vec<i64>),
+}
+
+/// terms are separated by whitespace and all of them have to match
+pub fn parse_query(query: &str) -> Result<Vec<Term>> {
+    let tokens = parse_tokens()
+        .parse(query)
+        .map_err(|e| anyhow!("can't parse query '{query}': {e:?}"))?;
+    tokens.iter().map(|token| to_term(token)).collect()
+}
+
+fn to_term(token: &str) -> Result<Term> {
+    match token.split_once(':') {
+        Some((key, value)) => match key.to_lowercase().as_str() {
+            "deck" => Ok(Term::Deck(value.into())),
+            // yomichan calls the field expression by default
+            "word" | "expression" => Ok(Term::Word(value.into())),
+            "reading" => Ok(Term::Reading(value.into())),
+            "nid" => value
+                .split(',')
+                .map(|id| id.parse().map_err(|_| anyhow!("invalid note id '{id}'")))
+                .collect::<Result<_>>()
+                .map(Term::NoteIds),
+            _ => Err(anyhow!("unsupported search term '{token}'")),
+        },
+        None => Ok(Term::Word(token.into())),
+    }
+}
+
+/// splits the query on whitespace, quotes can be around whole terms
+/// like `"deck:a b"` or just values like `deck:"a b"`
+fn parse_tokens() -> impl Parser<char, Vec<String>, Error = Simple<char>> {
+    let quoted = just('\\')
+        .ignore_then(any())
+        .or(filter(|c: &char| *c != '"' && *c != '\\'))
+        .repeated()
+        .delimited_by(just('"'), just('"'))
+        .collect::<String>();
+    let bare = filter(|c: &char| !c.is_whitespace() && *c != '"').map(String::from);
+    quoted
+        .or(bare)
+        .repeated()
+        .at_least(1)
+        .map(|pieces| pieces.concat())
+        .padded()
+        .repeated()
+        .then_ignore(end())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_query_test() {
+        let parsed = parse_query(r#""deck:jpdb" "word:見事" reading:"み ごと""#).unwrap();
+        assert_eq!(
+            vec![
+                Term::Deck("jpdb".into()),
+                Term::Word("見事".into()),
+                Term::Reading("み ごと".into())
+            ],
+            parsed
+        );
+    }
+
+    #[test]
+    fn parse_query_expression_test() {
+        let parsed = parse_query(r#""expression:てっきり""#).unwrap();
+        assert_eq!(vec![Term::Word("てっきり".into())], parsed);
+    }
+
+    #[test]
+    fn parse_query_note_id_test() {
+        let parsed = parse_query("nid:6075647459264299592").unwrap();
+        assert_eq!(vec![Term::NoteIds(vec![6075647459264299592])], parsed);
+        let parsed = parse_query("nid:1,2,3").unwrap();
+        assert_eq!(vec![Term::NoteIds(vec![1, 2, 3])], parsed);
+        assert!(parse_query("nid:abc").is_err());
+        assert!(parse_query("nid:1,,2").is_err());
+    }
+
+    #[test]
+    fn parse_query_unsupported_test() {
+        assert!(parse_query("added:1").is_err());
+    }
+}
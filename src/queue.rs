@@ -0,0 +1,122 @@
+//! notes which couldn't be added because jpdb was unreachable, they are added later
+use crate::anki_connect::Note;
+use crate::jpdb::MiningOptions;
+use crate::json_file::{now, JsonFile};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// every profile has its own queue, so notes are added to the right account
+pub fn file_name(profile: Option<&str>) -> String {
+    match profile {
+        Some(name) => format!("jpdb_connect_queue_{name}.json"),
+        None => "jpdb_connect_queue.json".into(),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueuedNote {
+    pub note: Note,
+    /// the options when the note was mined, None for notes queued by older versions
+    #[serde(default)]
+    pub options: Option<MiningOptions>,
+    /// unix time in seconds
+    pub queued: u64,
+}
+
+#[derive(Clone)]
+pub struct MiningQueue {
+    notes: JsonFile<Vec<QueuedNote>>,
+    /// only one replay at a time, otherwise notes could be added twice
+    pub replaying: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Default for MiningQueue {
+    fn default() -> Self {
+        MiningQueue::load(None)
+    }
+}
+
+impl MiningQueue {
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let queue = MiningQueue {
+            notes: JsonFile::load("queue", path),
+            replaying: Default::default(),
+        };
+        if !queue.is_empty() {
+            info!("{} notes are waiting to be added", queue.notes().len());
+        }
+        queue
+    }
+
+    pub fn push(&self, note: Note, options: MiningOptions) {
+        let queued = QueuedNote {
+            note,
+            options: Some(options),
+            queued: now(),
+        };
+        self.notes.update(|notes| notes.push(queued));
+    }
+
+    pub fn front(&self) -> Option<QueuedNote> {
+        self.notes.read(|notes| notes.first().cloned())
+    }
+
+    pub fn pop_front(&self) {
+        self.notes.update(|notes| {
+            if !notes.is_empty() {
+                notes.remove(0);
+            }
+        });
+    }
+
+    pub fn notes(&self) -> Vec<QueuedNote> {
+        self.notes.read(Vec::clone)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.notes.read(Vec::is_empty)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(word: &str) -> Note {
+        let json =
+            format!(r#"{{"deckName": "Mining", "fields": {{"word": "{word}", "sentence": ""}}}}"#);
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn queue_test() {
+        let path = std::env::temp_dir().join(format!("jpdb_connect_queue_{}", std::process::id()));
+        let queue = MiningQueue::load(Some(path.clone()));
+        assert!(queue.is_empty());
+        let options = MiningOptions {
+            auto_add: Some(3),
+            auto_forq: true,
+            ..MiningOptions::default()
+        };
+        queue.push(note("見事"), MiningOptions::default());
+        queue.push(note("てっきり"), options.clone());
+        queue.pop_front();
+
+        let loaded = MiningQueue::load(Some(path.clone()));
+        std::fs::remove_file(path).unwrap();
+        let notes = loaded.notes();
+        assert_eq!(1, notes.len());
+        assert_eq!("てっきり", notes[0].note.fields.word);
+        assert_eq!(Some("Mining"), notes[0].note.deck_name.as_deref());
+        assert_eq!(Some(options), notes[0].options);
+    }
+
+    #[test]
+    fn old_queue_test() {
+        let json = r#"[{"note": {"fields": {"word": "見事", "sentence": ""}}, "queued": 0}]"#;
+        let notes: Vec<QueuedNote> = serde_json::from_str(json).unwrap();
+        assert!(notes[0].options.is_none());
+    }
+}
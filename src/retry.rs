@@ -0,0 +1,138 @@
+//! tries requests again when jpdb is briefly overloaded or the network hiccups
+use log::*;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Method, Request, Response, StatusCode};
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+use tower::retry::Policy;
+
+/// waiting longer than this blocks every other request, the queue is better at waiting
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    attempts_left: u32,
+    /// doubles after every retry
+    backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts_left: 3,
+            backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// how long to wait before the next try, None if it shouldn't be tried again
+    /// GETs are retried after anything that might go away
+    /// POSTs only if jpdb can't have handled them, so nothing happens twice
+    fn delay(&self, req: &Request, result: Result<&Response, &reqwest::Error>) -> Option<Duration> {
+        if self.attempts_left == 0 {
+            return None;
+        }
+        let idempotent = req.method() == Method::GET;
+        match result {
+            Ok(res) => {
+                let status = res.status();
+                let retry = status == StatusCode::TOO_MANY_REQUESTS
+                    || status == StatusCode::SERVICE_UNAVAILABLE
+                    || (idempotent && status.is_server_error());
+                if !retry {
+                    return None;
+                }
+                let retry_after = res
+                    .headers()
+                    .get(RETRY_AFTER)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.trim().parse().ok())
+                    .map(Duration::from_secs);
+                // retries skip the rate limit, so they never go faster than the backoff
+                match retry_after {
+                    Some(after) if after > MAX_RETRY_AFTER => None,
+                    Some(after) => Some(after.max(self.backoff)),
+                    None => Some(self.backoff),
+                }
+            }
+            Err(e) if e.is_connect() || (idempotent && e.is_timeout()) => Some(self.backoff),
+            Err(_) => None,
+        }
+    }
+}
+
+impl Policy<Request, Response, reqwest::Error> for RetryPolicy {
+    type Future = Pin<Box<dyn Future<Output = Self> + Send>>;
+
+    fn retry(
+        &self,
+        req: &Request,
+        result: Result<&Response, &reqwest::Error>,
+    ) -> Option<Self::Future> {
+        let delay = self.delay(req, result)?;
+        let reason = match result {
+            Ok(res) => format!("status {}", res.status().as_u16()),
+            Err(e) => e.to_string(),
+        };
+        warn!(
+            "{} {} failed with {}, retrying in {}s",
+            req.method(),
+            req.url(),
+            reason,
+            delay.as_secs_f32()
+        );
+        let next = RetryPolicy {
+            attempts_left: self.attempts_left - 1,
+            backoff: self.backoff * 2,
+        };
+        Some(Box::pin(async move {
+            tokio::time::sleep(delay).await;
+            next
+        }))
+    }
+
+    fn clone_request(&self, req: &Request) -> Option<Request> {
+        req.try_clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::http;
+
+    fn request(method: Method) -> Request {
+        Request::new(method, "https://jpdb.io/".parse().unwrap())
+    }
+
+    fn response(status: u16, retry_after: Option<&str>) -> Response {
+        let mut res = http::Response::builder().status(status);
+        if let Some(after) = retry_after {
+            res = res.header("retry-after", after);
+        }
+        res.body("").unwrap().into()
+    }
+
+    #[test]
+    fn delay_test() {
+        let policy = RetryPolicy::default();
+        let get = request(Method::GET);
+        let post = request(Method::POST);
+        let delay = |req, status, after| policy.delay(req, Ok(&response(status, after)));
+        assert_eq!(Some(Duration::from_secs(1)), delay(&get, 500, None));
+        assert_eq!(None, delay(&post, 500, None));
+        assert_eq!(Some(Duration::from_secs(5)), delay(&post, 429, Some("5")));
+        assert_eq!(Some(Duration::from_secs(1)), delay(&get, 429, Some("0")));
+        assert_eq!(None, delay(&get, 503, Some("3600")));
+        assert_eq!(None, delay(&get, 404, None));
+        assert_eq!(None, delay(&get, 200, None));
+
+        let exhausted = RetryPolicy {
+            attempts_left: 0,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(None, exhausted.delay(&get, Ok(&response(503, None))));
+    }
+}
@@ -0,0 +1,153 @@
+//! session ids and keys, they can come from env vars or files and never show up in logs
+use anyhow::{Context, Result};
+use serde::{Deserialize, Deserializer};
+use std::ffi::OsString;
+use std::fmt;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// the secrets of the config, the logger replaces them, longest first
+static KNOWN: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// shorter secrets aren't replaced, they could be part of any text
+const MIN_LEN: usize = 8;
+
+/// a string which is printed as `***`
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        let value = value.into();
+        let mut known = KNOWN.lock().unwrap();
+        if value.len() >= MIN_LEN && !known.contains(&value) {
+            known.push(value.clone());
+            // a secret could contain a shorter one
+            known.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+        }
+        Secret(value)
+    }
+
+    /// it was removed from the config, logs don't need to hide it anymore
+    pub fn forget(&self) {
+        KNOWN.lock().unwrap().retain(|secret| secret != &self.0);
+    }
+
+    /// the actual value, only for sending it to jpdb or comparing it
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// the file content without surrounding whitespace, editors like to add a newline
+    pub fn read(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("can't read the secret file {}", path.display()))?;
+        Ok(Secret::new(content.trim()))
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("***")
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Secret::new)
+    }
+}
+
+/// looks up environment variables, tests use their own instead of changing the real ones
+pub type Env<'a> = &'a dyn Fn(&str) -> Option<OsString>;
+
+/// the environment of the process
+pub fn process_env(name: &str) -> Option<OsString> {
+    std::env::var_os(name)
+}
+
+/// the secret from, in this order, the env var `name`, the file in the env var `name_FILE`,
+/// the file from the config or the config itself
+pub fn resolve(
+    env: Env,
+    name: &str,
+    file: Option<&Path>,
+    value: Option<Secret>,
+) -> Result<Option<Secret>> {
+    let var = env(name).and_then(|v| v.into_string().ok());
+    if let Some(var) = var.filter(|v| !v.is_empty()) {
+        return Ok(Some(Secret::new(var)));
+    }
+    if let Some(path) = env(&format!("{name}_FILE")).filter(|p| !p.is_empty()) {
+        return Secret::read(Path::new(&path)).map(Some);
+    }
+    if let Some(path) = file {
+        return Secret::read(path).map(Some);
+    }
+    Ok(value)
+}
+
+/// the text with every known secret replaced by `***`
+pub fn redact(text: &str) -> String {
+    let known = KNOWN.lock().unwrap();
+    let mut text = text.to_string();
+    for secret in known.iter() {
+        if text.contains(secret.as_str()) {
+            text = text.replace(secret.as_str(), "***");
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secret_test() {
+        let secret: Secret = serde_json::from_str(r#""hunter2-secret-test""#).unwrap();
+        assert_eq!("hunter2-secret-test", secret.expose());
+        assert_eq!("Some(***)", format!("{:?}", Some(secret)));
+        assert_eq!(
+            "cookie sid=*** sent",
+            redact("cookie sid=hunter2-secret-test sent")
+        );
+
+        let path = std::env::temp_dir().join(format!("jpdb_connect_secret_{}", std::process::id()));
+        std::fs::write(&path, "from-file-secret-test\n").unwrap();
+        let env = |_: &str| None;
+        let resolved = resolve(&env, "JPDB_CONNECT_SECRET_TEST", Some(&path), None).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!("from-file-secret-test", resolved.unwrap().expose());
+        assert!(resolve(&env, "JPDB_CONNECT_SECRET_TEST", Some(&path), None).is_err());
+        let value = Some(Secret::new("from-config-secret-test"));
+        let resolved = resolve(&env, "JPDB_CONNECT_SECRET_TEST", None, value.clone()).unwrap();
+        assert_eq!(value, resolved);
+
+        let env =
+            |name: &str| (name == "JPDB_CONNECT_SECRET_TEST").then(|| "from-env-secret".into());
+        let resolved = resolve(&env, "JPDB_CONNECT_SECRET_TEST", None, value).unwrap();
+        assert_eq!("from-env-secret", resolved.unwrap().expose());
+    }
+
+    #[test]
+    fn known_test() {
+        let short = Secret::new("sid");
+        assert_eq!("sid=sid", redact("sid=sid"));
+        assert_eq!("sid", short.expose());
+
+        let secret = Secret::new("forgotten-secret-test");
+        Secret::new("forgotten-secret-test");
+        let count = || {
+            let known = KNOWN.lock().unwrap();
+            known
+                .iter()
+                .filter(|s| *s == "forgotten-secret-test")
+                .count()
+        };
+        assert_eq!(1, count());
+        secret.forget();
+        assert_eq!(0, count());
+        assert_eq!("forgotten-secret-test", redact("forgotten-secret-test"));
+    }
+}